@@ -1,25 +1,154 @@
-use rcgen::Certificate;
+use crate::{
+    proxy::{
+        fingerprint, read_certs, read_private_key, write_private_file, CertificateAuthority,
+        KeyAlgorithm,
+    },
+    CaExportArgs, CaGenerateArgs, CaPairArgs, CaShowArgs, KeyPassphraseArgs,
+};
+use anyhow::{bail, Context, Result};
+use rcgen::{Certificate, DistinguishedName, DnType};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
 
-use std::{fs, path::Path};
+/// Environment variable holding the CA private key passphrase.
+const KEY_PASSPHRASE_ENV: &str = "DEVICECHECK_KEY_PASSPHRASE";
 
-use crate::proxy::CertificateAuthority;
+/// Validity of a certificate authority generated implicitly by `run`.
+const DEFAULT_CA_DAYS: u32 = 3650;
 
-pub fn gen_ca<T: AsRef<Path>>(ca: T, key: T) -> Certificate {
-    let cert = CertificateAuthority::gen_ca().expect("generate cert");
-    let cert_crt = cert.serialize_pem().unwrap();
+/// Generate a certificate authority with the default subject.
+pub fn gen_ca<T: AsRef<Path>>(ca: T, key: T) -> Result<()> {
+    let cert = CertificateAuthority::gen_ca(
+        CertificateAuthority::default_subject(),
+        DEFAULT_CA_DAYS,
+        KeyAlgorithm::default(),
+    )
+    .context("generate cert")?;
 
-    fs::create_dir("ca").unwrap();
+    let fingerprint = write_ca(&cert, ca.as_ref(), key.as_ref(), false)?;
+    tracing::info!("CA generated, SHA-256 fingerprint: {}", fingerprint);
 
-    println!("{}", cert_crt);
-    if let Err(err) = fs::write(ca, cert_crt) {
-        tracing::error!("cert file write failed: {}", err);
+    Ok(())
+}
+
+/// Generate a certificate authority
+pub fn generate(args: CaGenerateArgs) -> Result<()> {
+    let mut subject = DistinguishedName::new();
+    subject.push(DnType::CommonName, args.common_name);
+    if let Some(organization) = args.organization {
+        subject.push(DnType::OrganizationName, organization);
+    }
+    if let Some(country) = args.country {
+        subject.push(DnType::CountryName, country);
+    }
+
+    let cert =
+        CertificateAuthority::gen_ca(subject, args.days, args.key_alg).context("generate cert")?;
+    let fingerprint = write_ca(&cert, &args.cert, &args.key, args.force)?;
+
+    println!("Certificate: {}", args.cert.display());
+    println!("Private key: {}", args.key.display());
+    println!("SHA-256 fingerprint: {}", fingerprint);
+
+    Ok(())
+}
+
+/// Show the details of a certificate authority
+pub fn show(args: CaShowArgs) -> Result<()> {
+    let cert = read_cert(&args.cert)?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|_| anyhow::anyhow!("Failed to parse CA certificate"))?;
+
+    println!("Subject: {}", parsed.subject());
+    println!("Issuer: {}", parsed.issuer());
+    println!("Serial: {}", parsed.raw_serial_as_string());
+    println!("Not before: {}", parsed.validity().not_before);
+    println!("Not after: {}", parsed.validity().not_after);
+    println!("CA: {}", parsed.is_ca());
+    println!("SHA-256 fingerprint: {}", fingerprint(&cert));
+
+    Ok(())
+}
+
+/// Export the certificate of a certificate authority
+pub fn export(args: CaExportArgs) -> Result<()> {
+    let cert = read_cert(&args.cert)?;
+    let exported = args
+        .format
+        .encode(&cert)
+        .context("Failed to export CA certificate")?;
+
+    match args.output {
+        Some(output) => fs::write(&output, exported)
+            .with_context(|| format!("Failed to write {}", output.display()))?,
+        None => io::stdout().write_all(&exported)?,
     }
 
-    let private_key = cert.serialize_private_key_pem();
-    println!("{}", private_key);
-    if let Err(err) = fs::write(key, private_key) {
-        tracing::error!("private key file write failed: {}", err);
+    Ok(())
+}
+
+/// Verify that a certificate and private key form a usable certificate authority
+pub fn verify(args: CaPairArgs) -> Result<()> {
+    let cert = read_cert(&args.cert)?;
+    let passphrase = read_passphrase(&args.passphrase)?;
+    let key = read_private_key(&args.key, passphrase.as_deref())?;
+
+    CertificateAuthority::validate(&key, &cert).context("CA certificate and key do not match")?;
+
+    println!("OK: {} matches {}", args.key.display(), args.cert.display());
+    println!("SHA-256 fingerprint: {}", fingerprint(&cert));
+
+    Ok(())
+}
+
+/// Write the certificate and private key of `cert`, returning the certificate fingerprint.
+///
+/// The private key is only readable by the current user.
+fn write_ca(cert: &Certificate, ca: &Path, key: &Path, force: bool) -> Result<String> {
+    for path in [ca, key] {
+        if !force && path.exists() {
+            bail!(
+                "{} already exists, use --force to overwrite",
+                path.display()
+            );
+        }
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
     }
 
-    cert
+    // Serialize once, every serialization produces a new signature
+    let der = rustls::Certificate(cert.serialize_der()?);
+    fs::write(
+        ca,
+        pem::encode(&pem::Pem::new("CERTIFICATE", der.0.clone())),
+    )
+    .with_context(|| format!("cert file write failed: {}", ca.display()))?;
+
+    write_private_file(key, cert.serialize_private_key_pem().as_bytes())
+        .with_context(|| format!("private key file write failed: {}", key.display()))?;
+
+    Ok(fingerprint(&der))
+}
+
+/// Resolve the CA private key passphrase from a file, or else the environment. It is never taken
+/// from the command line, where other users could see it.
+pub fn read_passphrase(args: &KeyPassphraseArgs) -> Result<Option<String>> {
+    match &args.key_passphrase_file {
+        Some(path) => fs::read_to_string(path)
+            .map(|passphrase| Some(passphrase.trim_end_matches(['\r', '\n']).to_owned()))
+            .with_context(|| format!("Failed to read {}", path.display())),
+        None => Ok(std::env::var(KEY_PASSPHRASE_ENV).ok()),
+    }
+}
+
+fn read_cert(path: &Path) -> Result<rustls::Certificate> {
+    read_certs(path)?
+        .into_iter()
+        .next()
+        .with_context(|| format!("No certificate found in {}", path.display()))
 }
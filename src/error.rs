@@ -1,5 +1,6 @@
+use http::StatusCode;
 use rcgen::Error as RcgenError;
-use std::io;
+use std::{error::Error as StdError, fmt, io, path::PathBuf, time::Duration};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -8,17 +9,366 @@ pub enum Error {
     Tls(#[from] RcgenError),
 
     #[error(transparent)]
-    HyperError(#[from] hyper::Error),
+    Rustls(#[from] rustls::Error),
 
     #[error(transparent)]
-    BodyErrpr(#[from] http::Error),
+    Hyper(#[from] hyper::Error),
 
     #[error(transparent)]
-    RequestConnectError(#[from] reqwest::Error),
+    Body(#[from] http::Error),
+
+    #[error(transparent)]
+    RequestConnect(#[from] reqwest::Error),
 
     #[error(transparent)]
     IO(#[from] io::Error),
 
     #[error(transparent)]
-    SerdeError(#[from] serde_json::Error),
+    Serde(#[from] serde_json::Error),
+
+    #[error("failed to resolve {host}: {source}")]
+    Dns { host: String, source: io::Error },
+
+    #[error("{0} timed out after {1:?}")]
+    Timeout(TimeoutKind, Duration),
+
+    #[error("{failure}: {source}")]
+    Upstream {
+        failure: UpstreamFailure,
+        source: Box<Error>,
+    },
+
+    #[error("unsupported upstream proxy {0}, expected an http, socks5 or socks5h URL")]
+    UnsupportedProxy(String),
+
+    #[error("leaf certificate of {0} matches no pinned public key")]
+    PinMismatch(String),
+
+    #[error("unsupported private key type")]
+    UnsupportedKey,
+
+    #[error("private key does not match the certificate")]
+    KeyMismatch,
+
+    #[error("failed to read {path}: {source}")]
+    ReadFile { path: PathBuf, source: io::Error },
+
+    #[error("no certificate found in {0}")]
+    NoCertificate(PathBuf),
+
+    #[error("no private key found in {0}")]
+    NoPrivateKey(PathBuf),
+
+    #[error("private key is encrypted, a passphrase is required")]
+    PassphraseRequired,
+
+    #[error("failed to decrypt private key: {0}")]
+    KeyDecryption(pkcs8::Error),
+
+    #[error("legacy encrypted PEM keys are not supported, convert the key to encrypted PKCS#8")]
+    LegacyEncryptedKey,
+}
+
+impl Error {
+    /// What went wrong talking to the upstream server, for errors classified by
+    /// [`Error::into_upstream`].
+    pub fn upstream_failure(&self) -> Option<UpstreamFailure> {
+        match self {
+            Error::Upstream { failure, .. } => Some(*failure),
+            _ => None,
+        }
+    }
+
+    /// Classify an error talking to an upstream server by its cause.
+    pub fn into_upstream(self) -> Error {
+        if let Error::Upstream { .. } = self {
+            return self;
+        }
+
+        Error::Upstream {
+            failure: UpstreamFailure::of(&self),
+            source: Box::new(self),
+        }
+    }
+}
+
+/// Which limit a connection or request exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// Connecting to the upstream server or proxy
+    Connect,
+    /// Waiting for the upstream response headers
+    ResponseHeaders,
+    /// The whole upstream exchange
+    Request,
+    /// Waiting for the next request of a client
+    ClientIdle,
+    /// Relaying nothing through a tunnel
+    TunnelIdle,
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TimeoutKind::Connect => "upstream connect",
+            TimeoutKind::ResponseHeaders => "upstream response headers",
+            TimeoutKind::Request => "upstream request",
+            TimeoutKind::ClientIdle => "idle client connection",
+            TimeoutKind::TunnelIdle => "idle tunnel",
+        })
+    }
+}
+
+/// Why a request to an upstream server failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamFailure {
+    /// The host name could not be resolved
+    Dns,
+    /// The connection was refused or could not be established
+    Connect,
+    /// The TLS handshake failed, e.g. because the certificate was rejected
+    Tls,
+    /// The upstream server did not answer in time
+    Timeout,
+    /// The upstream server broke the protocol or closed the connection
+    Protocol,
+}
+
+impl UpstreamFailure {
+    /// The status of the response sent to the client instead of the upstream response.
+    pub fn status(self) -> StatusCode {
+        match self {
+            UpstreamFailure::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// A stable identifier of the failure, for clients to match on.
+    pub fn code(self) -> &'static str {
+        match self {
+            UpstreamFailure::Dns => "dns_error",
+            UpstreamFailure::Connect => "connect_error",
+            UpstreamFailure::Tls => "tls_error",
+            UpstreamFailure::Timeout => "timeout",
+            UpstreamFailure::Protocol => "protocol_error",
+        }
+    }
+
+    /// Find the cause of `err` by walking its sources.
+    fn of(err: &Error) -> Self {
+        // Causes found deeper in the chain, such as a DNS query timing out, are more specific than
+        // timeouts and I/O error kinds
+        let mut fallback = None;
+        let mut next: Option<&(dyn StdError + 'static)> = Some(err);
+
+        while let Some(err) = next {
+            next = err.source();
+
+            if let Some(err) = err.downcast_ref::<Error>() {
+                match err {
+                    Error::Dns { .. } => return UpstreamFailure::Dns,
+                    Error::Timeout(..) => return UpstreamFailure::Timeout,
+                    Error::Upstream { failure, .. } => return *failure,
+                    Error::Rustls(_) => return UpstreamFailure::Tls,
+                    // Transparent errors skip themselves in their sources
+                    Error::IO(err) => next = Some(err),
+                    Error::Hyper(err) => next = Some(err),
+                    Error::RequestConnect(err) => next = Some(err),
+                    _ => {}
+                }
+            } else if err.is::<rustls::Error>() {
+                return UpstreamFailure::Tls;
+            } else if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+                if err.is_timeout() {
+                    fallback = Some(UpstreamFailure::Timeout);
+                } else if err.is_connect() {
+                    fallback = fallback.or(Some(UpstreamFailure::Connect));
+                }
+            } else if let Some(err) = err.downcast_ref::<hyper::Error>() {
+                if err.is_timeout() {
+                    fallback = Some(UpstreamFailure::Timeout);
+                } else if err.is_connect() {
+                    fallback = fallback.or(Some(UpstreamFailure::Connect));
+                }
+            } else if let Some(err) = err.downcast_ref::<io::Error>() {
+                let failure = match err.kind() {
+                    io::ErrorKind::TimedOut => Some(UpstreamFailure::Timeout),
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::AddrNotAvailable => {
+                        Some(UpstreamFailure::Connect)
+                    }
+                    _ if is_unreachable(err) => Some(UpstreamFailure::Connect),
+                    _ => None,
+                };
+                // A timeout while connecting is a timeout
+                if failure == Some(UpstreamFailure::Timeout) || fallback.is_none() {
+                    fallback = failure.or(fallback);
+                }
+                // The payload of a custom error is not among its sources
+                if let Some(inner) = err.get_ref() {
+                    next = Some(inner);
+                }
+            }
+        }
+
+        fallback.unwrap_or(UpstreamFailure::Protocol)
+    }
+}
+
+/// Returns `true` if `err` reports an unreachable host or network. Their error kinds are newer
+/// than the minimum supported Rust version, so the OS error codes are matched instead.
+#[cfg(target_family = "unix")]
+fn is_unreachable(err: &io::Error) -> bool {
+    use nix::libc::{EHOSTUNREACH, ENETUNREACH};
+
+    matches!(err.raw_os_error(), Some(EHOSTUNREACH | ENETUNREACH))
+}
+
+/// Returns `true` if `err` reports an unreachable host or network. Their error kinds are newer
+/// than the minimum supported Rust version, so the OS error codes are matched instead.
+#[cfg(not(target_family = "unix"))]
+fn is_unreachable(err: &io::Error) -> bool {
+    // WSAENETUNREACH and WSAEHOSTUNREACH
+    matches!(err.raw_os_error(), Some(10051 | 10065))
+}
+
+impl fmt::Display for UpstreamFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UpstreamFailure::Dns => "upstream host could not be resolved",
+            UpstreamFailure::Connect => "upstream connection failed",
+            UpstreamFailure::Tls => "TLS handshake with upstream failed",
+            UpstreamFailure::Timeout => "upstream timed out",
+            UpstreamFailure::Protocol => "upstream protocol error",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::client::connect::dns::Name;
+    use reqwest::dns::{Resolve, Resolving};
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::net::TcpListener;
+
+    /// An address nothing listens on.
+    async fn closed_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn classify(err: impl Into<Error>) -> (UpstreamFailure, &'static str, StatusCode) {
+        let failure = err.into().into_upstream().upstream_failure().unwrap();
+        (failure, failure.code(), failure.status())
+    }
+
+    /// Fails every lookup the way the upstream resolver does.
+    struct FailingResolver;
+
+    impl Resolve for FailingResolver {
+        fn resolve(&self, name: Name) -> Resolving {
+            let err = Error::Dns {
+                host: name.as_str().to_owned(),
+                source: io::Error::new(io::ErrorKind::NotFound, "no addresses"),
+            };
+            Box::pin(async move { Err(io::Error::new(io::ErrorKind::NotFound, err).into()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn dns_errors() {
+        let client = reqwest::Client::builder()
+            .dns_resolver(Arc::new(FailingResolver))
+            .build()
+            .unwrap();
+        let err = client.get("http://example.test/").send().await.unwrap_err();
+        assert_eq!(
+            classify(err),
+            (UpstreamFailure::Dns, "dns_error", StatusCode::BAD_GATEWAY)
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_refused() {
+        let url = format!("http://{}/", closed_addr().await);
+        let expected = (
+            UpstreamFailure::Connect,
+            "connect_error",
+            StatusCode::BAD_GATEWAY,
+        );
+
+        let err = reqwest::get(&url).await.unwrap_err();
+        assert_eq!(classify(err), expected);
+
+        let err = hyper::Client::new()
+            .get(url.parse().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(classify(err), expected);
+
+        #[cfg(target_family = "unix")]
+        for code in [nix::libc::EHOSTUNREACH, nix::libc::ENETUNREACH] {
+            assert_eq!(classify(io::Error::from_raw_os_error(code)), expected);
+        }
+
+        let err = tokio::net::TcpStream::connect(closed_addr().await)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(classify(err), expected);
+    }
+
+    #[tokio::test]
+    async fn timeouts() {
+        let expected = (
+            UpstreamFailure::Timeout,
+            "timeout",
+            StatusCode::GATEWAY_TIMEOUT,
+        );
+        let timeout = Duration::from_millis(50);
+
+        let err = Error::Timeout(TimeoutKind::ResponseHeaders, timeout);
+        assert_eq!(classify(err), expected);
+
+        // As raised by the connect timeout, within an I/O error
+        let err = Error::Timeout(TimeoutKind::Connect, timeout);
+        let err = io::Error::new(io::ErrorKind::TimedOut, err);
+        assert_eq!(classify(err), expected);
+
+        // A server that accepts but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let client = reqwest::Client::builder().timeout(timeout).build().unwrap();
+        let err = client.get(url).send().await.unwrap_err();
+        assert_eq!(classify(err), expected);
+    }
+
+    #[test]
+    fn tls_errors() {
+        let expected = (UpstreamFailure::Tls, "tls_error", StatusCode::BAD_GATEWAY);
+        let rustls_error =
+            || rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer);
+
+        assert_eq!(classify(rustls_error()), expected);
+        // As reported by a TLS stream
+        let err = io::Error::new(io::ErrorKind::InvalidData, rustls_error());
+        assert_eq!(classify(err), expected);
+    }
+
+    #[test]
+    fn other_errors_are_protocol_errors() {
+        let err = io::Error::new(io::ErrorKind::UnexpectedEof, "closed");
+        assert_eq!(
+            classify(err),
+            (
+                UpstreamFailure::Protocol,
+                "protocol_error",
+                StatusCode::BAD_GATEWAY
+            )
+        );
+
+        // Already classified errors keep their failure
+        let err = Error::Timeout(TimeoutKind::Request, Duration::from_secs(1)).into_upstream();
+        assert_eq!(classify(err).0, UpstreamFailure::Timeout);
+    }
 }
@@ -6,6 +6,11 @@ mod serve;
 
 use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
+use proxy::{
+    ConnectAction, DnsServer, ExportFormat, HostAddrs, HostPattern, HostRule, HttpVersionPolicy,
+    KeyAlgorithm, Origin, SocksAuth, SpkiPins, Upstreams,
+};
+use regex::Regex;
 use reqwest::Url;
 use std::{net::SocketAddr, path::PathBuf};
 
@@ -39,6 +44,21 @@ pub enum Commands {
     /// Show the server daemon process
     #[cfg(target_family = "unix")]
     PS,
+    /// Manage the MITM certificate authority
+    #[clap(subcommand)]
+    Ca(CaCommands),
+}
+
+#[derive(Subcommand)]
+pub enum CaCommands {
+    /// Generate a new CA certificate and private key
+    Generate(CaGenerateArgs),
+    /// Show the CA certificate details
+    Show(CaShowArgs),
+    /// Export the CA certificate
+    Export(CaExportArgs),
+    /// Verify that the CA certificate and private key match
+    Verify(CaPairArgs),
 }
 
 #[derive(Args, Clone, Debug)]
@@ -51,10 +71,82 @@ pub struct BootArgs {
     #[clap(short, long, default_value = "0.0.0.0:1080")]
     pub bind: SocketAddr,
 
-    /// Upstream proxy
+    /// Upstream proxy for hosts matching no --route, as http://, socks5:// or socks5h:// with optional USER:PASS@.
+    /// Without --proxy and --route, HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY are honoured
     #[clap(short, long)]
     pub proxy: Option<Url>,
 
+    /// Upstream route as PATTERN=UPSTREAM[,UPSTREAM...], first match wins, where UPSTREAM is 'direct' or a proxy URL.
+    /// Several upstreams fail over in order (e.g. '*=http://b:3128,socks5h://c:1080')
+    #[clap(long = "route", value_name = "PATTERN=UPSTREAMS")]
+    pub routes: Vec<HostRule<Upstreams>>,
+
+    /// Seconds between checks that upstream proxies are reachable, 0 disables
+    #[clap(long, value_name = "SECS", default_value_t = 30)]
+    pub health_check_interval: u64,
+
+    /// Resolve matching upstream hosts to fixed addresses, as PATTERN=IP[,IP...], first match wins.
+    /// Takes precedence over --hosts-file
+    #[clap(long = "host", value_name = "PATTERN=IPS")]
+    pub hosts: Vec<HostRule<HostAddrs>>,
+
+    /// Hosts file of fixed addresses for upstream hosts, in the /etc/hosts format
+    #[clap(long)]
+    pub hosts_file: Option<PathBuf>,
+
+    /// DNS server for upstream hosts: 'system', tcp://IP[:PORT] or a DNS over HTTPS URL
+    #[clap(long, default_value_t = DnsServer::System)]
+    pub dns_server: DnsServer,
+
+    /// Longest time in seconds resolved addresses are cached for, shorter if their TTL is, 0 disables
+    #[clap(long, value_name = "SECS", default_value_t = 60)]
+    pub dns_cache_ttl: u64,
+
+    /// Seconds connecting to an upstream server or proxy may take, 0 disables
+    #[clap(long, value_name = "SECS", default_value_t = 10)]
+    pub connect_timeout: u64,
+
+    /// Seconds to wait for upstream response headers after sending the whole request, 0 disables
+    #[clap(long, value_name = "SECS", default_value_t = 60)]
+    pub response_timeout: u64,
+
+    /// Seconds an upstream request may take until its response body is received, 0 disables
+    #[clap(long, value_name = "SECS", default_value_t = 0)]
+    pub request_timeout: u64,
+
+    /// Seconds an HTTP/1 client connection may wait for its next request, 0 disables
+    #[clap(long, value_name = "SECS", default_value_t = 120)]
+    pub client_idle_timeout: u64,
+
+    /// Seconds a tunnel may relay nothing before it is closed, 0 disables
+    #[clap(long, value_name = "SECS", default_value_t = 600)]
+    pub tunnel_idle_timeout: u64,
+
+    /// Seconds to wait for the client of an intercepted tunnel to speak first, before assuming a server-first protocol such as SMTP and relaying it untouched, 0 waits indefinitely
+    #[clap(long, value_name = "SECS", default_value_t = 30)]
+    pub server_first_timeout: u64,
+
+    /// CA certificate file trusted for upstream servers in addition to the built-in roots, PEM or DER
+    #[clap(long = "upstream-ca", value_name = "PATH")]
+    pub upstream_cas: Vec<PathBuf>,
+
+    /// Skip certificate verification of upstream hosts matching PATTERN
+    #[clap(long = "insecure-upstream", value_name = "PATTERN")]
+    pub insecure_upstreams: Vec<HostPattern>,
+
+    /// Pin upstream public keys as PATTERN=sha256/BASE64[,...], first match wins.
+    /// The leaf certificate presented by matching hosts must have one of the keys
+    #[clap(long = "pin", value_name = "PATTERN=PINS")]
+    pub pins: Vec<HostRule<SpkiPins>>,
+
+    /// Client certificate chain presented to upstream servers requesting one
+    #[clap(long, requires = "upstream_client_key")]
+    pub upstream_client_cert: Option<PathBuf>,
+
+    /// Unencrypted private key of --upstream-client-cert
+    #[clap(long, requires = "upstream_client_cert")]
+    pub upstream_client_key: Option<PathBuf>,
+
     /// MITM server CA certificate file path
     #[clap(long, default_value = "ca/cert.crt", requires = "bind")]
     pub cert: PathBuf,
@@ -62,13 +154,169 @@ pub struct BootArgs {
     /// MITM server CA private key file path
     #[clap(long, default_value = "ca/key.pem", requires = "bind")]
     pub key: PathBuf,
+
+    #[clap(flatten)]
+    pub passphrase: KeyPassphraseArgs,
+
+    /// MITM server CA chain file path, holding the intermediates above `--cert` and the root
+    #[clap(long)]
+    pub ca_chain: Option<PathBuf>,
+
+    /// Key algorithm of the key pair shared by issued leaf certificates
+    #[clap(long, value_enum, default_value_t = KeyAlgorithm::EcdsaP256)]
+    pub leaf_key_alg: KeyAlgorithm,
+
+    /// Directory where issued certificates are persisted across restarts
+    #[clap(long)]
+    pub cert_cache_dir: Option<PathBuf>,
+
+    /// Copy the subject, SANs and expiry of the upstream server certificate into issued certificates
+    #[clap(long)]
+    pub mimic_upstream_cert: bool,
+
+    /// Verify the upstream server certificate before the client handshake, and present an invalid
+    /// certificate to the client if it fails, so that the client rejects it as it would without the proxy
+    #[clap(long)]
+    pub verify_upstream_cert: bool,
+
+    /// ALPN protocols offered to intercepted TLS clients, in order of preference
+    #[clap(long, value_delimiter = ',', default_value = "h2,http/1.1")]
+    pub alpn: Vec<String>,
+
+    /// Upstream HTTP version for matching hosts, as PATTERN=auto|http1|http2 (e.g. *.example.com=http1)
+    #[clap(long = "http-version", value_name = "PATTERN=VERSION")]
+    pub http_versions: Vec<HostRule<HttpVersionPolicy>>,
+
+    /// CONNECT rule as PATTERN=intercept|passthrough|block, first match wins (e.g. '*.apple.com=passthrough').
+    /// PATTERN is an exact host, '*.domain', '.domain', a glob or 'regex:<expr>'
+    #[clap(long = "rule", value_name = "PATTERN=ACTION")]
+    pub rules: Vec<HostRule<ConnectAction>>,
+
+    /// Tunnel hosts untouched after their clients reject the issued certificate this many times, 0 disables
+    #[clap(long, default_value_t = 0)]
+    pub passthrough_after: u32,
+
+    /// Drop relayed WebSocket text messages matching REGEX, in either direction
+    #[clap(long = "ws-drop", value_name = "REGEX")]
+    pub ws_drops: Vec<Regex>,
+
+    /// Replace matches of REGEX in relayed WebSocket text messages with REPLACEMENT, where $1 or ${name} insert a group
+    #[clap(long = "ws-replace", num_args = 2, value_names = ["REGEX", "REPLACEMENT"])]
+    pub ws_replaces: Vec<String>,
+
+    /// SOCKS5/SOCKS4a bind address, served alongside the HTTP proxy
+    #[clap(long)]
+    pub socks_bind: Option<SocketAddr>,
+
+    /// Require SOCKS5 username/password authentication as USER:PASS, SOCKS4 is then refused. Ignored without --socks-bind
+    #[clap(
+        long,
+        value_name = "USER:PASS",
+        env = "DEVICECHECK_SOCKS_AUTH",
+        hide_env_values = true
+    )]
+    pub socks_auth: Option<SocksAuth>,
+
+    /// Transparent proxy bind address for connections redirected by iptables REDIRECT or TPROXY rules (Linux only)
+    #[clap(long)]
+    pub transparent: Option<SocketAddr>,
+
+    /// Reverse proxy every request received on `--reverse-bind` to this origin (e.g. https://api.example.com)
+    #[clap(long, value_name = "ORIGIN", requires = "reverse_bind")]
+    pub reverse: Option<Origin>,
+
+    /// Reverse proxy bind address
+    #[clap(long, requires = "reverse")]
+    pub reverse_bind: Option<SocketAddr>,
+
+    /// Terminate TLS on the reverse proxy listener with a certificate issued by the CA
+    #[clap(long, requires = "reverse")]
+    pub reverse_tls: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct KeyPassphraseArgs {
+    /// File containing the passphrase of an encrypted CA private key, which is otherwise read from the DEVICECHECK_KEY_PASSPHRASE environment variable
+    #[clap(long)]
+    pub key_passphrase_file: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct CaGenerateArgs {
+    /// CA certificate output path
+    #[clap(long, default_value = "ca/cert.crt")]
+    pub cert: PathBuf,
+
+    /// CA private key output path
+    #[clap(long, default_value = "ca/key.pem")]
+    pub key: PathBuf,
+
+    /// Subject common name
+    #[clap(long, default_value = "devicecheck-mitm")]
+    pub common_name: String,
+
+    /// Subject organization name
+    #[clap(long)]
+    pub organization: Option<String>,
+
+    /// Subject country name
+    #[clap(long)]
+    pub country: Option<String>,
+
+    /// Validity in days
+    #[clap(long, default_value_t = 3650)]
+    pub days: u32,
+
+    /// Key algorithm
+    #[clap(long, value_enum, default_value_t = KeyAlgorithm::EcdsaP256)]
+    pub key_alg: KeyAlgorithm,
+
+    /// Overwrite existing files
+    #[clap(short, long)]
+    pub force: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct CaShowArgs {
+    /// CA certificate file path
+    #[clap(long, default_value = "ca/cert.crt")]
+    pub cert: PathBuf,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct CaExportArgs {
+    /// CA certificate file path
+    #[clap(long, default_value = "ca/cert.crt")]
+    pub cert: PathBuf,
+
+    /// Export format
+    #[clap(long, value_enum, default_value_t = ExportFormat::Pem)]
+    pub format: ExportFormat,
+
+    /// Output file path, defaults to stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct CaPairArgs {
+    /// CA certificate file path
+    #[clap(long, default_value = "ca/cert.crt")]
+    pub cert: PathBuf,
+
+    /// CA private key file path
+    #[clap(long, default_value = "ca/key.pem")]
+    pub key: PathBuf,
+
+    #[clap(flatten)]
+    pub passphrase: KeyPassphraseArgs,
 }
 
 fn main() -> Result<()> {
     let opt = Opt::parse();
 
     match opt.commands {
-        Commands::Run(args) =>  serve::Serve(args).run()?,
+        Commands::Run(args) => serve::Serve(args).run()?,
         #[cfg(target_family = "unix")]
         Commands::Start(args) => daemon::start(args)?,
         #[cfg(target_family = "unix")]
@@ -79,6 +327,12 @@ fn main() -> Result<()> {
         Commands::PS => daemon::status()?,
         #[cfg(target_family = "unix")]
         Commands::Log => daemon::log()?,
+        Commands::Ca(command) => match command {
+            CaCommands::Generate(args) => cagen::generate(args)?,
+            CaCommands::Show(args) => cagen::show(args)?,
+            CaCommands::Export(args) => cagen::export(args)?,
+            CaCommands::Verify(args) => cagen::verify(args)?,
+        },
     };
 
     Ok(())
@@ -1,20 +1,93 @@
+use super::{cert_store::CertStore, keys};
 use crate::error::Error;
-use moka::sync::Cache;
+use moka::{sync::Cache, Expiry};
 use rand::{thread_rng, Rng};
 use rcgen::{
     BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
     Error as RcgenError, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SanType,
 };
-use std::sync::Arc;
+use rsa::pkcs8::EncodePrivateKey;
+use std::{
+    io,
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use time::{ext::NumericalDuration, OffsetDateTime};
 use tokio_rustls::rustls::{
-    server::{ClientHello, ResolvesServerCert},
-    sign::CertifiedKey,
-    ServerConfig,
+    server::{ClientHello, ResolvesServerCert, ServerSessionMemoryCache},
+    sign::{CertifiedKey, SigningKey},
+    ServerConfig, Ticketer,
 };
+use x509_parser::extensions::GeneralName;
 
+const DEFAULT_CA_NAME: &str = "devicecheck-mitm";
 const CERT_TTL_DAYS: u64 = 365;
 const CERT_CACHE_TTL_SECONDS: u64 = CERT_TTL_DAYS * 24 * 60 * 60 / 2;
+/// Shortest validity left in a mimicked certificate, whatever the upstream certificate has left.
+const MIMIC_MIN_VALIDITY_DAYS: i64 = 7;
+const SESSION_CACHE_SIZE: usize = 4096;
+
+/// How a deliberately invalid leaf certificate fails validation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InvalidLeaf {
+    /// The certificate expired yesterday
+    Expired,
+    /// The certificate is for a name the client did not ask for
+    WrongName,
+}
+
+/// Key algorithm used for generated key pairs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeyAlgorithm {
+    /// ECDSA with the NIST P-256 curve
+    #[default]
+    EcdsaP256,
+    /// Ed25519
+    Ed25519,
+    /// RSA with a 2048 bit modulus
+    Rsa2048,
+}
+
+impl KeyAlgorithm {
+    /// Generate a new key pair, returned as PKCS#8 DER.
+    pub fn generate(self) -> Result<rustls::PrivateKey, Error> {
+        let der = match self {
+            KeyAlgorithm::EcdsaP256 => {
+                KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?.serialize_der()
+            }
+            KeyAlgorithm::Ed25519 => KeyPair::generate(&rcgen::PKCS_ED25519)?.serialize_der(),
+            KeyAlgorithm::Rsa2048 => rsa::RsaPrivateKey::new(&mut thread_rng(), 2048)
+                .and_then(|key| key.to_pkcs8_der().map_err(Into::into))
+                .map_err(|_| RcgenError::KeyGenerationUnavailable)?
+                .as_bytes()
+                .to_vec(),
+        };
+        Ok(rustls::PrivateKey(der))
+    }
+}
+
+/// Key pair shared by all leaf certificates issued by the authority.
+///
+/// Keeping the leaf key separate from the CA key means an intercepted TLS session never proves
+/// possession of the root key.
+struct LeafKey {
+    private_key: rustls::PrivateKey,
+    signing_key: Arc<dyn SigningKey>,
+}
+
+impl LeafKey {
+    fn generate(alg: KeyAlgorithm) -> Result<LeafKey, Error> {
+        let private_key = alg.generate()?;
+        let signing_key =
+            rustls::sign::any_supported_type(&private_key).map_err(|_| Error::UnsupportedKey)?;
+        Ok(LeafKey {
+            private_key,
+            signing_key,
+        })
+    }
+}
 
 /// Issues certificates for use when communicating with clients.
 ///
@@ -23,71 +96,295 @@ const CERT_CACHE_TTL_SECONDS: u64 = CERT_TTL_DAYS * 24 * 60 * 60 / 2;
 /// either trust the provided root certificate, or to ignore certificate errors.
 #[derive(Clone)]
 pub struct CertificateAuthority {
-    private_key: rustls::PrivateKey,
+    signer: Arc<Certificate>,
     ca_cert: rustls::Certificate,
-    ca_cert_string: String,
+    root_cert: rustls::Certificate,
+    ca_chain: Vec<rustls::Certificate>,
+    leaf_key: Arc<LeafKey>,
     cache: Cache<String, Arc<CertifiedKey>>,
+    store: Option<Arc<CertStore>>,
 }
 
 impl CertificateAuthority {
-    pub fn gen_ca() -> Result<Certificate, RcgenError> {
-        let mut params = CertificateParams::default();
+    /// The subject used for generated certificate authorities unless configured otherwise.
+    pub fn default_subject() -> DistinguishedName {
         let mut distinguished_name = DistinguishedName::new();
-        distinguished_name.push(DnType::CommonName, "devicecheck-mitm");
-        distinguished_name.push(DnType::OrganizationName, "devicecheck-mitm");
+        distinguished_name.push(DnType::CommonName, DEFAULT_CA_NAME);
+        distinguished_name.push(DnType::OrganizationName, DEFAULT_CA_NAME);
         distinguished_name.push(DnType::CountryName, "CN");
         distinguished_name.push(DnType::LocalityName, "CN");
-        params.distinguished_name = distinguished_name;
+        distinguished_name
+    }
+
+    /// Generate a self-signed root certificate valid for `days` days from now.
+    pub fn gen_ca(
+        subject: DistinguishedName,
+        days: u32,
+        key_alg: KeyAlgorithm,
+    ) -> Result<Certificate, Error> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = subject;
+        params.serial_number = Some(thread_rng().gen::<u64>().into());
+        params.not_before = OffsetDateTime::now_utc().saturating_sub(1.days());
+        params.not_after = OffsetDateTime::now_utc().saturating_add(i64::from(days).days());
         params.key_usages = vec![
             KeyUsagePurpose::DigitalSignature,
             KeyUsagePurpose::KeyCertSign,
             KeyUsagePurpose::CrlSign,
         ];
         params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
-        Certificate::from_params(params)
+
+        let key_pair = KeyPair::from_der(&key_alg.generate()?.0)?;
+        params.alg = key_pair
+            .compatible_algs()
+            .next()
+            .ok_or(Error::UnsupportedKey)?;
+        params.key_pair = Some(key_pair);
+
+        Certificate::from_params(params).map_err(Into::into)
     }
 
     /// Attempts to create a new certificate authority.
     ///
     /// This will fail if the provided key or certificate is invalid, or if the key does not match
-    /// the certificate.
+    /// the certificate. A fresh leaf key pair of `leaf_key_alg` is generated and shared by every
+    /// certificate the authority issues.
     pub fn new(
         private_key: rustls::PrivateKey,
         ca_cert: rustls::Certificate,
-        ca_cert_string: String,
         cache_size: u64,
+        leaf_key_alg: KeyAlgorithm,
     ) -> Result<CertificateAuthority, Error> {
-        let ca = CertificateAuthority {
-            private_key,
+        Self::validate(&private_key, &ca_cert)?;
+
+        // Parse the CA once, it signs every issued certificate
+        let key_pair = KeyPair::from_der(&private_key.0)?;
+        let signer =
+            Certificate::from_params(CertificateParams::from_ca_cert_der(&ca_cert.0, key_pair)?)?;
+
+        Ok(CertificateAuthority {
+            signer: Arc::new(signer),
+            root_cert: ca_cert.clone(),
             ca_cert,
-            ca_cert_string,
+            ca_chain: Vec::new(),
+            leaf_key: Arc::new(LeafKey::generate(leaf_key_alg)?),
             cache: Cache::builder()
                 .max_capacity(cache_size)
-                .time_to_live(std::time::Duration::from_secs(CERT_CACHE_TTL_SECONDS))
+                .expire_after(CertExpiry)
                 .build(),
-        };
+            store: None,
+        })
+    }
+
+    /// Load a certificate authority from a certificate file and a private key file.
+    ///
+    /// All files may be PEM or DER encoded. The certificate file, and the optional `chain_path`,
+    /// may hold intermediates and the root. The certificate matching the private key signs issued
+    /// certificates, and the intermediates are presented along with them. The private
+    /// key may be PKCS#8, PKCS#1 or SEC1, and an encrypted PKCS#8 key is decrypted with
+    /// `passphrase`.
+    pub fn from_pem_files(
+        cert_path: &Path,
+        key_path: &Path,
+        chain_path: Option<&Path>,
+        passphrase: Option<&str>,
+        cache_size: u64,
+        leaf_key_alg: KeyAlgorithm,
+    ) -> Result<CertificateAuthority, Error> {
+        let mut certs = keys::read_certs(cert_path)?;
+        if let Some(chain_path) = chain_path {
+            certs.extend(keys::read_certs(chain_path)?);
+        }
+        let private_key = keys::read_private_key(key_path, passphrase)?;
+
+        let index = certs
+            .iter()
+            .position(|cert| Self::validate(&private_key, cert).is_ok())
+            .ok_or(Error::KeyMismatch)?;
+        let ca_cert = certs.remove(index);
+
+        let mut ca = Self::new(private_key, ca_cert, cache_size, leaf_key_alg)?;
+
+        // Clients trust the root, which is the top of the chain and is not presented
+        if let Some(root) = certs
+            .iter()
+            .rev()
+            .find(|cert| is_self_signed(cert))
+            .or(certs.last())
+        {
+            ca.root_cert = root.clone();
+        }
+        ca.ca_chain = std::iter::once(&ca.ca_cert)
+            .chain(certs.iter())
+            .filter(|cert| !is_self_signed(cert))
+            .cloned()
+            .collect();
 
-        ca.validate()?;
         Ok(ca)
     }
 
-    pub(crate) fn get_certified_key(&self, server_name: &str) -> Arc<CertifiedKey> {
-        if let Some(server_cfg) = self.cache.get(server_name) {
-            return server_cfg;
+    /// Persist issued certificates and their keys under `dir`.
+    ///
+    /// Stored certificates are reloaded lazily after a restart, so clients keep seeing the same
+    /// certificate for a host. Certificates issued by a different CA are removed.
+    pub fn with_cert_cache_dir(mut self, dir: &Path) -> Result<CertificateAuthority, Error> {
+        // Named after the CA signing the certificates, which may be an intermediate
+        let signer = fingerprint(&self.ca_cert);
+        self.store = Some(Arc::new(CertStore::open(dir, &signer)?));
+        Ok(self)
+    }
+
+    /// Resolve the certificate of `server_name`, issuing it on the blocking pool on a cache miss.
+    pub(crate) async fn resolve(
+        self: &Arc<Self>,
+        server_name: &str,
+    ) -> Result<Arc<CertifiedKey>, Error> {
+        let server_name = normalize_server_name(server_name);
+
+        if let Some(certified_key) = self.cache.get(&server_name) {
+            return Ok(certified_key);
+        }
+
+        let ca = self.clone();
+        tokio::task::spawn_blocking(move || ca.get_certified_key(&server_name))
+            .await
+            .map_err(|err| io::Error::other(err).into())
+    }
+
+    /// Get the certificate of `server_name` from memory or disk, or issue a new one.
+    ///
+    /// Concurrent misses for the same host share a single issuance.
+    fn get_certified_key(&self, server_name: &str) -> Arc<CertifiedKey> {
+        let server_name = normalize_server_name(server_name);
+
+        self.cache.get_with(server_name.clone(), || {
+            self.load_stored(&server_name).unwrap_or_else(|| {
+                let cert = self.gen_cert(&server_name);
+                self.issue(&server_name, cert)
+            })
+        })
+    }
+
+    /// Returns `true` if a certificate for `server_name` is already cached, reloading it from disk
+    /// on the blocking pool if it is stored.
+    pub(crate) async fn is_cached(self: &Arc<Self>, server_name: &str) -> bool {
+        let server_name = normalize_server_name(server_name);
+
+        if self.cache.contains_key(&server_name) {
+            return true;
+        }
+        if self.store.is_none() {
+            return false;
         }
 
-        let certs = vec![self.gen_cert(server_name)];
-        let key = rustls::sign::any_supported_type(&self.private_key)
-            .expect("parse any supported private key");
-        let certified_key = Arc::new(CertifiedKey::new(certs, key));
+        let ca = self.clone();
+        tokio::task::spawn_blocking(move || match ca.load_stored(&server_name) {
+            Some(certified_key) => {
+                ca.cache.insert(server_name, certified_key);
+                true
+            }
+            None => false,
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// Issue a certificate for `server_name` that mimics `upstream_cert` on the blocking pool.
+    ///
+    /// The subject, subject alternative names and expiry of `upstream_cert` are copied into the
+    /// issued certificate, which replaces any cached certificate for the host. An expiry that has
+    /// passed or is near is pushed out, so that the issued certificate stays usable.
+    pub(crate) async fn mimic(
+        self: &Arc<Self>,
+        server_name: &str,
+        upstream_cert: rustls::Certificate,
+    ) -> Result<Arc<CertifiedKey>, Error> {
+        let ca = self.clone();
+        let server_name = server_name.to_owned();
+        tokio::task::spawn_blocking(move || ca.mimic_certified_key(&server_name, &upstream_cert))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Issue a certificate for `server_name` that mimics the certificate of the upstream server.
+    fn mimic_certified_key(
+        &self,
+        server_name: &str,
+        upstream_cert: &rustls::Certificate,
+    ) -> Result<Arc<CertifiedKey>, Error> {
+        let server_name = normalize_server_name(server_name);
+
+        let params = mimic_params(self.leaf_params(&server_name), upstream_cert)?;
+        let cert = self.sign_cert(params);
+        let certified_key = self.issue(&server_name, cert);
 
-        self.cache
-            .insert(server_name.to_string(), certified_key.clone());
+        self.cache.insert(server_name, certified_key.clone());
 
-        certified_key
+        Ok(certified_key)
+    }
+
+    /// Issue a certificate for `server_name` that clients reject the way `invalid` describes, so
+    /// that an untrustworthy upstream server is not masked by a valid certificate.
+    ///
+    /// The certificate is neither cached nor stored.
+    pub(crate) fn invalid_certified_key(
+        &self,
+        server_name: &str,
+        invalid: InvalidLeaf,
+    ) -> Arc<CertifiedKey> {
+        let server_name = normalize_server_name(server_name);
+
+        let params = match invalid {
+            InvalidLeaf::Expired => {
+                let mut params = self.leaf_params(&server_name);
+                params.not_before = OffsetDateTime::now_utc().saturating_sub(2.days());
+                params.not_after = OffsetDateTime::now_utc().saturating_sub(1.days());
+                params
+            }
+            InvalidLeaf::WrongName => self.leaf_params(&format!(
+                "invalid-upstream-certificate.{DEFAULT_CA_NAME}.invalid"
+            )),
+        };
+
+        let certs = self.with_chain(self.sign_cert(params));
+        Arc::new(CertifiedKey::new(certs, self.leaf_key.signing_key.clone()))
+    }
+
+    /// Load the certificate of `server_name` from the on-disk store.
+    fn load_stored(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let (cert, key) = self.store.as_ref()?.load(server_name)?;
+        let signing_key = rustls::sign::any_supported_type(&key).ok()?;
+        Some(Arc::new(CertifiedKey::new(
+            self.with_chain(cert),
+            signing_key,
+        )))
+    }
+
+    /// Wrap the newly issued `cert` of `server_name`, persisting it if a store is configured.
+    fn issue(&self, server_name: &str, cert: rustls::Certificate) -> Arc<CertifiedKey> {
+        if let Some(store) = &self.store {
+            if let Err(err) = store.store(server_name, &cert, &self.leaf_key.private_key) {
+                tracing::warn!("Failed to store certificate of {}: {}", server_name, err);
+            }
+        }
+
+        let certs = self.with_chain(cert);
+        Arc::new(CertifiedKey::new(certs, self.leaf_key.signing_key.clone()))
+    }
+
+    /// The certificate chain presented for an issued `leaf` certificate, which includes the
+    /// signing certificate when it is an intermediate.
+    fn with_chain(&self, leaf: rustls::Certificate) -> Vec<rustls::Certificate> {
+        std::iter::once(leaf)
+            .chain(self.ca_chain.iter().cloned())
+            .collect()
     }
 
     fn gen_cert(&self, server_name: &str) -> rustls::Certificate {
+        self.sign_cert(self.leaf_params(server_name))
+    }
+
+    fn leaf_params(&self, server_name: &str) -> CertificateParams {
         let mut params = rcgen::CertificateParams::default();
 
         params.serial_number = Some(thread_rng().gen::<u64>().into());
@@ -95,7 +392,10 @@ impl CertificateAuthority {
         params.not_after = OffsetDateTime::now_utc().saturating_add((CERT_TTL_DAYS as i64).days());
         params
             .subject_alt_names
-            .push(SanType::DnsName(server_name.to_string()));
+            .push(match server_name.parse::<IpAddr>() {
+                Ok(ip) => SanType::IpAddress(ip),
+                Err(_) => SanType::DnsName(server_name.to_string()),
+            });
         let mut distinguished_name = DistinguishedName::new();
         distinguished_name.push(DnType::CommonName, server_name);
         params.distinguished_name = distinguished_name;
@@ -103,51 +403,277 @@ impl CertificateAuthority {
         params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
         params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
 
-        let key_pair = KeyPair::from_der(&self.private_key.0).expect("Failed to parse private key");
+        params
+    }
+
+    fn sign_cert(&self, mut params: CertificateParams) -> rustls::Certificate {
+        let key_pair = KeyPair::from_der(&self.leaf_key.private_key.0)
+            .expect("Failed to parse leaf private key");
         params.alg = key_pair
             .compatible_algs()
             .next()
             .expect("Failed to find compatible algorithm");
         params.key_pair = Some(key_pair);
 
-        let key_pair = KeyPair::from_der(&self.private_key.0).expect("Failed to parse private key");
-
-        let ca_cert_params = rcgen::CertificateParams::from_ca_cert_der(&self.ca_cert.0, key_pair)
-            .expect("Failed to parse CA certificate");
-        let ca_cert = rcgen::Certificate::from_params(ca_cert_params)
-            .expect("Failed to generate CA certificate");
-
         let cert = rcgen::Certificate::from_params(params).expect("Failed to generate certificate");
 
         rustls::Certificate(
-            cert.serialize_der_with_signer(&ca_cert)
+            cert.serialize_der_with_signer(&self.signer)
                 .expect("Failed to serialize certificate"),
         )
     }
 
-    fn validate(&self) -> Result<(), RcgenError> {
-        let key_pair = rcgen::KeyPair::from_der(&self.private_key.0)?;
-        rcgen::CertificateParams::from_ca_cert_der(&self.ca_cert.0, key_pair)?;
+    /// Check that `ca_cert` is a usable CA certificate and that `private_key` belongs to it.
+    pub fn validate(
+        private_key: &rustls::PrivateKey,
+        ca_cert: &rustls::Certificate,
+    ) -> Result<(), Error> {
+        let key_pair = rcgen::KeyPair::from_der(&private_key.0)?;
+        let public_key = key_pair.public_key_raw().to_vec();
+        rcgen::CertificateParams::from_ca_cert_der(&ca_cert.0, key_pair)?;
+
+        let (_, cert) = x509_parser::parse_x509_certificate(&ca_cert.0)
+            .map_err(|_| RcgenError::CouldNotParseCertificate)?;
+        if cert.public_key().subject_public_key.data != public_key {
+            return Err(Error::KeyMismatch);
+        }
+
         Ok(())
     }
 
-    pub fn get_cert(&self) -> String {
-        self.ca_cert_string.clone()
+    /// The SHA-256 fingerprint of the root certificate that clients should trust, rather than of
+    /// an intermediate signing the issued certificates.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.root_cert)
+    }
+
+    /// The root certificate that clients should trust.
+    pub fn root_cert(&self) -> &rustls::Certificate {
+        &self.root_cert
     }
 
-    pub fn gen_server_config(self: Arc<Self>) -> Arc<ServerConfig> {
-        let server_cfg = ServerConfig::builder()
+    /// Build the server config shared by all intercepted connections.
+    ///
+    /// Certificates are resolved by SNI from the certificate cache. Sessions can be resumed across
+    /// connections through a shared session cache and ticketer.
+    pub fn gen_server_config(
+        self: Arc<Self>,
+        alpn_protocols: Vec<Vec<u8>>,
+    ) -> Result<Arc<ServerConfig>, Error> {
+        let mut server_cfg = ServerConfig::builder()
             .with_safe_defaults()
             .with_no_client_auth()
             .with_cert_resolver(self);
+        server_cfg.session_storage = ServerSessionMemoryCache::new(SESSION_CACHE_SIZE);
+        server_cfg.ticketer = Ticketer::new()?;
+        server_cfg.alpn_protocols = alpn_protocols;
+        Ok(Arc::new(server_cfg))
+    }
+
+    /// Derive a server config from `server_config` that presents `certified_key` regardless of
+    /// SNI. The session cache and ticketer stay shared with `server_config`.
+    pub fn with_certified_key(
+        server_config: &ServerConfig,
+        certified_key: Arc<CertifiedKey>,
+    ) -> Arc<ServerConfig> {
+        let mut server_cfg = server_config.clone();
+        server_cfg.cert_resolver = Arc::new(ResolvedCert(certified_key));
         Arc::new(server_cfg)
     }
 }
 
+/// Keeps a certificate cached for at most half the validity of a freshly issued one, and never past
+/// a day before it expires, so that certificates reloaded from disk or mimicked from the upstream
+/// server are replaced before clients reject them.
+struct CertExpiry;
+
+impl Expiry<String, Arc<CertifiedKey>> for CertExpiry {
+    fn expire_after_create(
+        &self,
+        _server_name: &String,
+        certified_key: &Arc<CertifiedKey>,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(cache_lifetime(certified_key))
+    }
+
+    fn expire_after_update(
+        &self,
+        _server_name: &String,
+        certified_key: &Arc<CertifiedKey>,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(cache_lifetime(certified_key))
+    }
+}
+
+/// How long `certified_key` may stay cached from now on.
+fn cache_lifetime(certified_key: &CertifiedKey) -> Duration {
+    let Some(leaf) = certified_key.cert.first() else {
+        return Duration::ZERO;
+    };
+
+    x509_parser::parse_x509_certificate(&leaf.0)
+        .ok()
+        .and_then(|(_, cert)| {
+            let renew_at = cert.validity().not_after.to_datetime() - 1.days();
+            Duration::try_from(renew_at - OffsetDateTime::now_utc()).ok()
+        })
+        .unwrap_or(Duration::ZERO)
+        .min(Duration::from_secs(CERT_CACHE_TTL_SECONDS))
+}
+
+/// Resolves cached certificates only, since issuing one would block the handshake. Connections
+/// are accepted with a certificate resolved beforehand, see [`CertificateAuthority::resolve`].
 impl ResolvesServerCert for CertificateAuthority {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
-        client_hello
-            .server_name()
-            .map(|name| self.get_certified_key(name))
+        let server_name = normalize_server_name(client_hello.server_name()?);
+        self.cache.get(&server_name)
+    }
+}
+
+/// Resolves to a certificate issued before the handshake started.
+struct ResolvedCert(Arc<CertifiedKey>);
+
+impl ResolvesServerCert for ResolvedCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+/// Returns `true` if the subject and issuer of `cert` are the same.
+fn is_self_signed(cert: &rustls::Certificate) -> bool {
+    x509_parser::parse_x509_certificate(&cert.0)
+        .map(|(_, cert)| cert.subject().as_raw() == cert.issuer().as_raw())
+        .unwrap_or(false)
+}
+
+/// Format the SHA-256 digest of a DER encoded certificate as colon separated hex.
+pub fn fingerprint(cert: &rustls::Certificate) -> String {
+    ring::digest::digest(&ring::digest::SHA256, &cert.0)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Copy the subject, subject alternative names and expiry of `upstream_cert` into `params`.
+///
+/// The SAN list of `params` is kept when the upstream certificate has none, so that the issued
+/// certificate still matches the server name. The expiry is at least a week from now.
+fn mimic_params(
+    mut params: CertificateParams,
+    upstream_cert: &rustls::Certificate,
+) -> Result<CertificateParams, Error> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&upstream_cert.0)
+        .map_err(|_| RcgenError::CouldNotParseCertificate)?;
+
+    let mut distinguished_name = DistinguishedName::new();
+    for attr in cert.subject().iter_attributes() {
+        if let (Some(oid), Ok(value)) = (attr.attr_type().iter(), attr.as_str()) {
+            let oid = oid.collect::<Vec<_>>();
+            distinguished_name.push(DnType::from_oid(&oid), value);
+        }
+    }
+    params.distinguished_name = distinguished_name;
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        let subject_alt_names = san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(SanType::DnsName(name.to_string())),
+                GeneralName::IPAddress(&[a, b, c, d]) => {
+                    Some(SanType::IpAddress(IpAddr::from([a, b, c, d])))
+                }
+                GeneralName::IPAddress(octets) => <[u8; 16]>::try_from(*octets)
+                    .ok()
+                    .map(|octets| SanType::IpAddress(IpAddr::from(octets))),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !subject_alt_names.is_empty() {
+            params.subject_alt_names = subject_alt_names;
+        }
+    }
+
+    params.not_after = cert
+        .validity()
+        .not_after
+        .to_datetime()
+        .max(OffsetDateTime::now_utc() + MIMIC_MIN_VALIDITY_DAYS.days());
+
+    Ok(params)
+}
+
+/// Normalize a server name to the form used in issued certificates.
+///
+/// IP literals (including bracketed IPv6 hosts) are returned in canonical form, and hostnames are
+/// converted to lowercase ASCII, with internationalized labels punycode-encoded.
+fn normalize_server_name(server_name: &str) -> String {
+    let name = server_name
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.');
+
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return ip.to_string();
+    }
+
+    idna::domain_to_ascii(name).unwrap_or_else(|_| name.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ca() -> CertificateAuthority {
+        let alg = KeyAlgorithm::default();
+        let ca =
+            CertificateAuthority::gen_ca(CertificateAuthority::default_subject(), 30, alg).unwrap();
+        let private_key = rustls::PrivateKey(ca.serialize_private_key_der());
+        let ca_cert = rustls::Certificate(ca.serialize_der().unwrap());
+        CertificateAuthority::new(private_key, ca_cert, 16, alg).unwrap()
+    }
+
+    fn expiring_in(ca: &CertificateAuthority, days: i64) -> Arc<CertifiedKey> {
+        let mut params = ca.leaf_params("example.com");
+        params.not_after = OffsetDateTime::now_utc() + days.days();
+        ca.issue("example.com", ca.sign_cert(params))
+    }
+
+    #[test]
+    fn issued_certificate_is_cached_for_half_its_validity() {
+        let ca = ca();
+        let lifetime = cache_lifetime(&ca.get_certified_key("example.com"));
+        assert_eq!(lifetime, Duration::from_secs(CERT_CACHE_TTL_SECONDS));
+    }
+
+    #[test]
+    fn certificate_is_not_cached_past_its_expiry() {
+        let ca = ca();
+        let lifetime = cache_lifetime(&expiring_in(&ca, 10));
+        assert!(lifetime > Duration::from_secs(8 * 24 * 60 * 60));
+        assert!(lifetime <= Duration::from_secs(9 * 24 * 60 * 60));
+
+        assert_eq!(cache_lifetime(&expiring_in(&ca, -1)), Duration::ZERO);
+    }
+
+    #[test]
+    fn mimicked_certificate_outlives_expired_upstream() {
+        let ca = ca();
+        let upstream = expiring_in(&ca, -1);
+        let mimicked = ca
+            .mimic_certified_key("example.com", &upstream.cert[0])
+            .unwrap();
+
+        let (_, cert) = x509_parser::parse_x509_certificate(&mimicked.cert[0].0).unwrap();
+        let not_after = cert.validity().not_after.to_datetime();
+        assert!(not_after > OffsetDateTime::now_utc() + 6.days());
+        assert!(cache_lifetime(&mimicked) > Duration::ZERO);
     }
 }
@@ -0,0 +1,179 @@
+use super::keys;
+use crate::error::Error;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use time::{ext::NumericalDuration, OffsetDateTime};
+
+/// File marking a directory as created by the store.
+const MARKER: &str = ".devicecheck-cert-store";
+
+/// On-disk cache of issued leaf certificates and their private keys.
+///
+/// Each host is stored as one PEM file holding the leaf certificate and its private key, inside a
+/// directory named after the fingerprint of the signing CA. Directories of other CAs are removed
+/// when the store is opened, so a new CA never serves certificates issued by the previous one.
+/// Only directories holding the [`MARKER`] file the store writes are ever removed, in case the
+/// root is shared with other data.
+pub(crate) struct CertStore {
+    dir: PathBuf,
+}
+
+impl CertStore {
+    /// Open the store under `root` for the CA with the given SHA-256 `fingerprint`.
+    pub(crate) fn open(root: &Path, fingerprint: &str) -> Result<CertStore, Error> {
+        let name = fingerprint.replace(':', "").to_ascii_lowercase();
+        let dir = root.join(&name);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(MARKER), b"")?;
+
+        for entry in fs::read_dir(root)?.flatten() {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name != name
+                && is_fingerprint(&file_name)
+                && entry.path().join(MARKER).is_file()
+            {
+                tracing::info!("Removing certificates of previous CA: {}", file_name);
+                if let Err(err) = fs::remove_dir_all(entry.path()) {
+                    tracing::warn!("Failed to remove {}: {}", entry.path().display(), err);
+                }
+            }
+        }
+
+        Ok(CertStore { dir })
+    }
+
+    /// Load the certificate and private key stored for `server_name`.
+    ///
+    /// Certificates expiring within a day are removed and not returned.
+    pub(crate) fn load(
+        &self,
+        server_name: &str,
+    ) -> Option<(rustls::Certificate, rustls::PrivateKey)> {
+        let path = self.path(server_name);
+        if !path.exists() {
+            return None;
+        }
+
+        let loaded = keys::read_certs(&path)
+            .ok()
+            .and_then(|certs| certs.into_iter().next())
+            .zip(keys::read_private_key(&path, None).ok());
+
+        match loaded {
+            Some((cert, key)) if !expires_soon(&cert) => Some((cert, key)),
+            _ => {
+                tracing::debug!("Evicting stored certificate: {}", path.display());
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    /// Store the certificate and private key issued for `server_name`.
+    pub(crate) fn store(
+        &self,
+        server_name: &str,
+        cert: &rustls::Certificate,
+        key: &rustls::PrivateKey,
+    ) -> io::Result<()> {
+        let contents = pem::encode_many(&[
+            pem::Pem::new("CERTIFICATE", cert.0.clone()),
+            pem::Pem::new("PRIVATE KEY", key.0.clone()),
+        ]);
+        keys::write_private_file(&self.path(server_name), contents.as_bytes())
+    }
+
+    fn path(&self, server_name: &str) -> PathBuf {
+        let file_name = server_name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' | '.' | '-' => c,
+                _ => '_',
+            })
+            .collect::<String>();
+        self.dir.join(format!("{file_name}.pem"))
+    }
+}
+
+fn is_fingerprint(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn expires_soon(cert: &rustls::Certificate) -> bool {
+    x509_parser::parse_x509_certificate(&cert.0)
+        .map(|(_, cert)| {
+            cert.validity().not_after.to_datetime() < OffsetDateTime::now_utc() + 1.days()
+        })
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::keys::tests::TempDir;
+
+    const FINGERPRINT: &str = "AA:BB";
+
+    /// A certificate for `example.com` valid until `not_after`, and its private key.
+    fn issue(not_after: OffsetDateTime) -> (rustls::Certificate, rustls::PrivateKey) {
+        let mut params = rcgen::CertificateParams::new(vec!["example.com".to_owned()]);
+        params.not_after = not_after;
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        (
+            rustls::Certificate(cert.serialize_der().unwrap()),
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    #[test]
+    fn stored_certificates_are_loaded() {
+        let dir = TempDir::new("cert-store");
+        let store = CertStore::open(&dir.0, FINGERPRINT).unwrap();
+        let (cert, key) = issue(OffsetDateTime::now_utc() + 30.days());
+
+        assert!(store.load("example.com").is_none());
+        store.store("example.com", &cert, &key).unwrap();
+
+        // Also after opening the store again
+        let store = CertStore::open(&dir.0, FINGERPRINT).unwrap();
+        assert_eq!(store.load("example.com"), Some((cert, key)));
+        assert!(store.load("other.example.com").is_none());
+    }
+
+    #[test]
+    fn expiring_and_unreadable_certificates_are_evicted() {
+        let dir = TempDir::new("cert-store-evict");
+        let store = CertStore::open(&dir.0, FINGERPRINT).unwrap();
+        let (cert, key) = issue(OffsetDateTime::now_utc() + 12.hours());
+
+        store.store("example.com", &cert, &key).unwrap();
+        assert!(store.load("example.com").is_none());
+        assert!(!store.path("example.com").exists());
+
+        fs::write(store.path("broken.example.com"), b"not a certificate").unwrap();
+        assert!(store.load("broken.example.com").is_none());
+        assert!(!store.path("broken.example.com").exists());
+    }
+
+    #[test]
+    fn only_directories_of_the_store_are_removed() {
+        let dir = TempDir::new("cert-store-previous");
+        let previous = "ab".repeat(32);
+        let unrelated = "cd".repeat(32);
+
+        CertStore::open(&dir.0, &previous).unwrap();
+        fs::create_dir_all(dir.0.join(&unrelated)).unwrap();
+        dir.write(&format!("{unrelated}/data"), b"keep");
+
+        let store = CertStore::open(&dir.0, FINGERPRINT).unwrap();
+        assert!(store.dir.join(MARKER).is_file());
+        assert!(!dir.0.join(&previous).exists());
+        assert_eq!(
+            fs::read(dir.0.join(&unrelated).join("data")).unwrap(),
+            b"keep"
+        );
+    }
+}
@@ -1,55 +1,262 @@
-use crate::error::Error;
-use http::{response::Builder, Request, Response};
-use hyper::Body;
-use reqwest::{redirect::Policy, Client, Url};
+use super::{
+    connector::Connector, matcher::HostRules, timeout::Timeouts, upstream_tls::UpstreamTls,
+};
+use crate::error::{Error, TimeoutKind, UpstreamFailure};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use http::{request::Parts, response::Builder, Request, Response};
+use hyper::{body::HttpBody, Body};
+use moka::sync::Cache;
+use reqwest::{redirect::Policy, Client};
+use std::{net::IpAddr, sync::Arc, task::Poll, time::Duration};
+use tokio::sync::oneshot;
+use tokio_rustls::rustls::ClientConfig;
+
+/// Maximum number of transparent destinations whose clients are kept.
+const DESTINATION_CAPACITY: u64 = 256;
+/// How long the clients of a transparent destination are kept while unused.
+const DESTINATION_IDLE: Duration = Duration::from_secs(300);
+
+/// HTTP version used for upstream requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HttpVersionPolicy {
+    /// Negotiate HTTP/2 or HTTP/1.1 with the origin via ALPN
+    #[default]
+    Auto,
+    /// Always use HTTP/1.1
+    Http1,
+    /// Always use HTTP/2
+    Http2,
+}
 
 #[derive(Clone)]
 pub struct HttpClient {
-    inner: Client,
+    clients: Clients,
+    versions: HostRules<HttpVersionPolicy>,
+    connector: Connector,
+    timeouts: Timeouts,
+    /// TLS configurations of the clients, kept to build clients for other connectors
+    tls_configs: Arc<TlsConfigs>,
+    /// Clients of transparent connections, by the host name and address the clients connected to
+    destinations: Cache<(String, IpAddr), Clients>,
+}
+
+/// A client per upstream HTTP version policy, sharing a connector. Cheap to clone.
+#[derive(Clone)]
+struct Clients {
+    auto: Client,
+    http1: Client,
+    http2: Client,
+}
+
+struct TlsConfigs {
+    auto: ClientConfig,
+    http1: ClientConfig,
+    http2: ClientConfig,
 }
 
 impl HttpClient {
-    pub fn new(proxy: Option<Url>) -> Result<Self, Error> {
-        let mut builder = Client::builder();
+    pub(crate) fn new(
+        connector: Connector,
+        versions: HostRules<HttpVersionPolicy>,
+        tls: &UpstreamTls,
+        timeouts: Timeouts,
+    ) -> Result<Self, Error> {
+        let tls_configs = TlsConfigs {
+            auto: tls.client_config(&[b"h2", b"http/1.1"])?,
+            http1: tls.client_config(&[b"http/1.1"])?,
+            http2: tls.client_config(&[b"h2"])?,
+        };
 
-        if let Some(proxy) = proxy {
-            let proxy = reqwest::Proxy::all(proxy)?;
-            builder = builder.proxy(proxy);
-        }
+        Ok(Self {
+            clients: Clients::new(&connector, &tls_configs, &timeouts)?,
+            versions,
+            connector,
+            timeouts,
+            tls_configs: Arc::new(tls_configs),
+            destinations: Cache::builder()
+                .max_capacity(DESTINATION_CAPACITY)
+                .time_to_idle(DESTINATION_IDLE)
+                .build(),
+        })
+    }
+
+    /// A client for the connection of a transparent client, connecting to `ip`, the address the
+    /// client connected to, for `host`, the name it asked for. Its connections are pooled with
+    /// those of other clients connecting to the same name and address.
+    pub(crate) fn with_destination(&self, host: &str, ip: IpAddr) -> Result<Self, Error> {
+        let connector = self.connector.with_destination(host, ip);
+        let key = (host.to_ascii_lowercase(), ip);
+        let clients = match self.destinations.get(&key) {
+            Some(clients) => clients,
+            None => {
+                let clients = Clients::new(&connector, &self.tls_configs, &self.timeouts)?;
+                self.destinations.insert(key, clients.clone());
+                clients
+            }
+        };
+
+        Ok(Self {
+            clients,
+            connector,
+            ..self.clone()
+        })
+    }
+
+    /// Select the client for the upstream HTTP version configured for `host`.
+    fn client(&self, host: Option<&str>) -> &Client {
+        let policy = host
+            .and_then(|host| self.versions.get(host))
+            .copied()
+            .unwrap_or_default();
 
-        builder
-            .redirect(Policy::none())
-            .build()
-            .map(|inner| Self { inner })
-            .map_err(Into::into)
+        match policy {
+            HttpVersionPolicy::Auto => &self.clients.auto,
+            HttpVersionPolicy::Http1 => &self.clients.http1,
+            HttpVersionPolicy::Http2 => &self.clients.http2,
+        }
     }
 
     pub async fn http(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
         let (parts, body) = req.into_parts();
 
-        // Send request
-        let mut resp = self
-            .inner
-            .request(parts.method, parts.uri.to_string())
-            .headers(parts.headers)
-            .body(reqwest::Body::wrap_stream(body))
-            .send()
-            .await?;
+        // Without a body to send again, an idempotent request can fail over with the next requests
+        let replayable = parts.method.is_idempotent() && body.is_end_stream();
+        let mut body = Some(body);
+        let mut resp = loop {
+            let body = body.take().unwrap_or_else(Body::empty);
+            let err = match self.send(&parts, body).await? {
+                Ok(resp) => break resp,
+                Err(err) => err,
+            };
+
+            // Let the next requests fail over if the proxy is down. Only a request that can be
+            // retried waits to find out, the others leave the proxy to the health checks
+            if let Some(url) = err.url().filter(|_| err.is_connect()) {
+                if !replayable {
+                    self.connector.mark_proxy_down_for(url, &err);
+                } else if self.connector.check_proxy_for(url).await {
+                    tracing::debug!("Retrying {} {} on the next upstream", parts.method, url);
+                    continue;
+                }
+            }
+            return Err(request_error(err, &self.timeouts));
+        };
 
-        // Create response builder
+        // Create response builder, answering in the HTTP version the client used
         let mut builder = Builder::new()
             .status(resp.status())
-            .version(resp.version())
+            .version(parts.version)
             .extension(parts.extensions);
 
         // Move headers
-        builder
-            .headers_mut()
-            .map(|headers| headers.extend(std::mem::take(resp.headers_mut())));
-
-        // Build response
-        builder
-            .body(Body::wrap_stream(resp.bytes_stream()))
-            .map_err(Into::into)
+        if let Some(headers) = builder.headers_mut() {
+            headers.extend(std::mem::take(resp.headers_mut()));
+        }
+
+        // Build response, the request deadline still running while the body streams
+        let uri = parts.uri;
+        let timeouts = self.timeouts;
+        let body = resp.bytes_stream().map_err(move |err| {
+            let err = request_error(err, &timeouts);
+            if let Error::Timeout(..) = err {
+                tracing::warn!("Response body of {} failed: {}", uri, err);
+            }
+            err
+        });
+        builder.body(Body::wrap_stream(body)).map_err(Into::into)
+    }
+
+    /// Send the request of `parts` with `body` upstream, failing with the response headers
+    /// timeout if its response does not start in time.
+    async fn send(
+        &self,
+        parts: &Parts,
+        body: Body,
+    ) -> Result<reqwest::Result<reqwest::Response>, Error> {
+        // Send request, noting when its body has been sent, or right away if it has none
+        let (body_sent, sent) = oneshot::channel();
+        let mut body_sent = Some(body_sent).filter(|_| !body.is_end_stream());
+        let body = body.chain(stream::poll_fn(move |_| {
+            if let Some(body_sent) = body_sent.take() {
+                let _ = body_sent.send(());
+            }
+            Poll::Ready(None)
+        }));
+        let mut request = self
+            .client(parts.uri.host())
+            .request(parts.method.clone(), parts.uri.to_string())
+            .headers(parts.headers.clone())
+            .body(reqwest::Body::wrap_stream(body));
+        if let Some(timeout) = self.timeouts.get(TimeoutKind::Request) {
+            request = request.timeout(timeout);
+        }
+        let send = request.send();
+        match self.timeouts.get(TimeoutKind::ResponseHeaders) {
+            Some(timeout) => {
+                // Uploading the body is bounded by the request timeout only
+                let deadline = async {
+                    // The body is also done with once dropped
+                    let _ = sent.await;
+                    tokio::time::sleep(timeout).await
+                };
+                tokio::select! {
+                    result = send => Ok(result),
+                    () = deadline => Err(Error::Timeout(TimeoutKind::ResponseHeaders, timeout)),
+                }
+            }
+            None => Ok(send.await),
+        }
+    }
+}
+
+impl Clients {
+    /// Build the clients connecting through `connector`.
+    fn new(
+        connector: &Connector,
+        tls_configs: &TlsConfigs,
+        timeouts: &Timeouts,
+    ) -> Result<Self, Error> {
+        let builder = |tls_config: &ClientConfig| {
+            let resolver = connector.resolver();
+            let connector = connector.clone();
+            let proxy = reqwest::Proxy::custom(move |url| connector.proxy_for(url));
+            let builder = Client::builder()
+                .proxy(proxy)
+                .dns_resolver(resolver)
+                .use_preconfigured_tls(tls_config.clone())
+                .redirect(Policy::none());
+            match timeouts.get(TimeoutKind::Connect) {
+                Some(timeout) => builder.connect_timeout(timeout),
+                None => builder,
+            }
+        };
+
+        Ok(Self {
+            auto: builder(&tls_configs.auto).build()?,
+            http1: builder(&tls_configs.http1).http1_only().build()?,
+            http2: builder(&tls_configs.http2)
+                .http2_prior_knowledge()
+                .build()?,
+        })
+    }
+}
+
+/// The error of a request that failed with `err`, naming the limit of `timeouts` it exceeded if it
+/// timed out.
+fn request_error(err: reqwest::Error, timeouts: &Timeouts) -> Error {
+    let kind = if err.is_connect() {
+        TimeoutKind::Connect
+    } else {
+        TimeoutKind::Request
+    };
+    let timeout = timeouts.get(kind).filter(|_| err.is_timeout());
+
+    let err = Error::from(err).into_upstream();
+    match timeout {
+        // Failing to resolve the host is more specific, DNS queries time out on their own
+        Some(timeout) if err.upstream_failure() != Some(UpstreamFailure::Dns) => {
+            Error::Timeout(kind, timeout)
+        }
+        _ => err,
     }
 }
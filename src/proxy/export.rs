@@ -0,0 +1,367 @@
+use crate::error::Error;
+use rand::{thread_rng, Rng};
+use ring::{digest, hmac};
+use yasna::{models::ObjectIdentifier, Tag};
+
+/// `id-data` from RFC 2315.
+const DATA_OID: &[u64] = &[1, 2, 840, 113549, 1, 7, 1];
+/// `certBag` from RFC 7292.
+const CERT_BAG_OID: &[u64] = &[1, 2, 840, 113549, 1, 12, 10, 1, 3];
+/// `x509Certificate` from RFC 7292.
+const X509_CERTIFICATE_OID: &[u64] = &[1, 2, 840, 113549, 1, 9, 22, 1];
+/// `friendlyName` from RFC 2985.
+const FRIENDLY_NAME_OID: &[u64] = &[1, 2, 840, 113549, 1, 9, 20];
+/// `id-sha256` from RFC 5754.
+const SHA256_OID: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+
+/// Iterations of the PKCS#12 MAC key derivation.
+const MAC_ITERATIONS: u32 = 2048;
+
+const FILE_STEM: &str = "auth-mitm";
+
+/// Format in which the CA certificate is exported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// PEM encoded certificate
+    #[default]
+    Pem,
+    /// DER encoded certificate
+    Der,
+    /// PKCS#12 bundle holding only the certificate, with an empty password
+    P12,
+    /// Apple configuration profile installing the certificate as a trusted root
+    Mobileconfig,
+}
+
+impl ExportFormat {
+    /// The MIME type of the exported certificate.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Pem | ExportFormat::Der => "application/x-x509-ca-cert",
+            ExportFormat::P12 => "application/x-pkcs12",
+            ExportFormat::Mobileconfig => "application/x-apple-aspen-config",
+        }
+    }
+
+    /// The file name of the exported certificate.
+    pub fn file_name(self) -> String {
+        let extension = match self {
+            ExportFormat::Pem => "crt",
+            ExportFormat::Der => "cer",
+            ExportFormat::P12 => "p12",
+            ExportFormat::Mobileconfig => "mobileconfig",
+        };
+        format!("{FILE_STEM}.{extension}")
+    }
+
+    /// Encode the DER certificate `cert` in this format.
+    pub fn encode(self, cert: &rustls::Certificate) -> Result<Vec<u8>, Error> {
+        Ok(match self {
+            ExportFormat::Pem => pem::encode(&pem::Pem::new("CERTIFICATE", cert.0.clone())).into(),
+            ExportFormat::Der => cert.0.clone(),
+            ExportFormat::P12 => pkcs12(cert, &common_name(cert)?),
+            ExportFormat::Mobileconfig => mobileconfig(cert, &common_name(cert)?).into(),
+        })
+    }
+}
+
+fn common_name(cert: &rustls::Certificate) -> Result<String, Error> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|_| rcgen::Error::CouldNotParseCertificate)?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .unwrap_or(FILE_STEM)
+        .to_owned();
+    Ok(common_name)
+}
+
+/// Build an unencrypted PKCS#12 bundle holding `cert`, integrity protected with an empty
+/// password as most importers require.
+fn pkcs12(cert: &rustls::Certificate, friendly_name: &str) -> Vec<u8> {
+    let safe_contents = yasna::construct_der(|writer| {
+        writer.write_sequence_of(|writer| {
+            writer.next().write_sequence(|writer| {
+                writer.next().write_oid(&oid(CERT_BAG_OID));
+                writer.next().write_tagged(Tag::context(0), |writer| {
+                    writer.write_sequence(|writer| {
+                        writer.next().write_oid(&oid(X509_CERTIFICATE_OID));
+                        writer.next().write_tagged(Tag::context(0), |writer| {
+                            writer.write_bytes(&cert.0);
+                        });
+                    });
+                });
+                writer.next().write_set_of(|writer| {
+                    writer.next().write_sequence(|writer| {
+                        writer.next().write_oid(&oid(FRIENDLY_NAME_OID));
+                        writer.next().write_set_of(|writer| {
+                            writer.next().write_bmp_string(friendly_name);
+                        });
+                    });
+                });
+            });
+        });
+    });
+
+    let auth_safe = yasna::construct_der(|writer| {
+        writer.write_sequence_of(|writer| {
+            writer.next().write_sequence(|writer| {
+                writer.next().write_oid(&oid(DATA_OID));
+                writer.next().write_tagged(Tag::context(0), |writer| {
+                    writer.write_bytes(&safe_contents);
+                });
+            });
+        });
+    });
+
+    let salt = thread_rng().gen::<[u8; 8]>();
+    let mac_key = pkcs12_mac_key(&salt, MAC_ITERATIONS);
+    let mac = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &mac_key), &auth_safe);
+
+    yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer.next().write_u32(3);
+            writer.next().write_sequence(|writer| {
+                writer.next().write_oid(&oid(DATA_OID));
+                writer.next().write_tagged(Tag::context(0), |writer| {
+                    writer.write_bytes(&auth_safe);
+                });
+            });
+            writer.next().write_sequence(|writer| {
+                writer.next().write_sequence(|writer| {
+                    writer.next().write_sequence(|writer| {
+                        writer.next().write_oid(&oid(SHA256_OID));
+                        writer.next().write_null();
+                    });
+                    writer.next().write_bytes(mac.as_ref());
+                });
+                writer.next().write_bytes(&salt);
+                writer.next().write_u32(MAC_ITERATIONS);
+            });
+        });
+    })
+}
+
+/// Derive the SHA-256 MAC key of an empty password, following RFC 7292 appendix B.2.
+///
+/// The key is exactly one digest long, so a single round of the derivation is enough.
+fn pkcs12_mac_key(salt: &[u8], iterations: u32) -> Vec<u8> {
+    const BLOCK_LEN: usize = 64;
+    const MAC_ID: u8 = 3;
+
+    // An empty password is the BMPString null terminator
+    let password = [0u8; 2];
+
+    let mut input = vec![MAC_ID; BLOCK_LEN];
+    input.extend(
+        salt.iter()
+            .cycle()
+            .take(BLOCK_LEN * salt.len().div_ceil(BLOCK_LEN)),
+    );
+    input.extend(password.iter().cycle().take(BLOCK_LEN));
+
+    let mut key = digest::digest(&digest::SHA256, &input).as_ref().to_vec();
+    for _ in 1..iterations {
+        key = digest::digest(&digest::SHA256, &key).as_ref().to_vec();
+    }
+    key
+}
+
+/// Build an Apple configuration profile installing `cert` as a trusted root.
+///
+/// The payload identifiers are derived from the certificate, so installing the profile again
+/// replaces the previous one.
+fn mobileconfig(cert: &rustls::Certificate, display_name: &str) -> String {
+    use base64::Engine;
+
+    let digest = digest::digest(&digest::SHA256, &cert.0);
+    let cert_uuid = uuid(&digest.as_ref()[..16]);
+    let profile_uuid = uuid(&digest.as_ref()[16..]);
+    let display_name = xml_escape(display_name);
+    let data = base64::engine::general_purpose::STANDARD.encode(&cert.0);
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>PayloadContent</key>
+	<array>
+		<dict>
+			<key>PayloadCertificateFileName</key>
+			<string>{FILE_STEM}.cer</string>
+			<key>PayloadContent</key>
+			<data>{data}</data>
+			<key>PayloadDescription</key>
+			<string>Adds a CA root certificate</string>
+			<key>PayloadDisplayName</key>
+			<string>{display_name}</string>
+			<key>PayloadIdentifier</key>
+			<string>com.apple.security.root.{cert_uuid}</string>
+			<key>PayloadType</key>
+			<string>com.apple.security.root</string>
+			<key>PayloadUUID</key>
+			<string>{cert_uuid}</string>
+			<key>PayloadVersion</key>
+			<integer>1</integer>
+		</dict>
+	</array>
+	<key>PayloadDisplayName</key>
+	<string>{display_name}</string>
+	<key>PayloadIdentifier</key>
+	<string>devicecheck.mitm.{profile_uuid}</string>
+	<key>PayloadRemovalDisallowed</key>
+	<false/>
+	<key>PayloadType</key>
+	<string>Configuration</string>
+	<key>PayloadUUID</key>
+	<string>{profile_uuid}</string>
+	<key>PayloadVersion</key>
+	<integer>1</integer>
+</dict>
+</plist>
+"#
+    )
+}
+
+/// Format 16 bytes as an RFC 4122 version 4 UUID.
+fn uuid(bytes: &[u8]) -> String {
+    let mut bytes = <[u8; 16]>::try_from(bytes).expect("uuid is 16 bytes");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().map(|b| format!("{b:02X}")).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn oid(components: &[u64]) -> ObjectIdentifier {
+    ObjectIdentifier::from_slice(components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert() -> rustls::Certificate {
+        let params = rcgen::CertificateParams::new(vec!["example.com".to_owned()]);
+        rustls::Certificate(
+            rcgen::Certificate::from_params(params)
+                .unwrap()
+                .serialize_der()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn pkcs12_mac_key_known_answer() {
+        // openssl kdf -keylen 32 -kdfopt digest:SHA256 -kdfopt hexpass:0000
+        //     -kdfopt hexsalt:0102030405060708 -kdfopt iter:<N> -kdfopt id:3 PKCS12KDF
+        let salt = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(
+            pkcs12_mac_key(&salt, 1),
+            b"\x89\xba\x2b\xd5\xa6\xa1\x89\x69\x1b\x3e\x79\xa1\x79\x3e\xaf\xd7\
+              \x79\x30\x15\x59\xb6\x55\x8e\x3d\xb9\x1f\x23\xba\x06\x98\x60\x60"
+        );
+        assert_eq!(
+            pkcs12_mac_key(&salt, 2048),
+            b"\x87\x8f\x85\xff\x6d\xb5\xc9\x2f\x2d\xa8\x73\xfd\xb0\xec\xae\x4b\
+              \xdc\x9d\xcc\x3c\xb5\xfa\xf9\x22\x6d\x10\xee\x23\xaf\x02\x54\x6a"
+        );
+    }
+
+    #[test]
+    fn pkcs12_parses_and_mac_verifies() {
+        let cert = cert();
+        let pfx = ExportFormat::P12.encode(&cert).unwrap();
+
+        let (auth_safe, digest_oid, mac, salt, iterations) = yasna::parse_der(&pfx, |reader| {
+            reader.read_sequence(|reader| {
+                assert_eq!(reader.next().read_u32()?, 3);
+                let auth_safe = reader.next().read_sequence(|reader| {
+                    assert_eq!(reader.next().read_oid()?, oid(DATA_OID));
+                    reader
+                        .next()
+                        .read_tagged(Tag::context(0), |reader| reader.read_bytes())
+                })?;
+                let (digest_oid, mac, salt, iterations) =
+                    reader.next().read_sequence(|reader| {
+                        let (digest_oid, mac) = reader.next().read_sequence(|reader| {
+                            let digest_oid = reader.next().read_sequence(|reader| {
+                                let digest_oid = reader.next().read_oid()?;
+                                reader.next().read_null()?;
+                                Ok(digest_oid)
+                            })?;
+                            Ok((digest_oid, reader.next().read_bytes()?))
+                        })?;
+                        let salt = reader.next().read_bytes()?;
+                        let iterations = reader.next().read_u32()?;
+                        Ok((digest_oid, mac, salt, iterations))
+                    })?;
+                Ok((auth_safe, digest_oid, mac, salt, iterations))
+            })
+        })
+        .unwrap();
+
+        assert_eq!(digest_oid, oid(SHA256_OID));
+        assert_eq!(iterations, MAC_ITERATIONS);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &pkcs12_mac_key(&salt, iterations));
+        hmac::verify(&key, &auth_safe, &mac).expect("MAC verifies");
+
+        // The only safe bag holds the certificate, named after its subject
+        let safe_contents = yasna::parse_der(&auth_safe, |reader| {
+            reader.read_sequence(|reader| {
+                reader.next().read_sequence(|reader| {
+                    assert_eq!(reader.next().read_oid()?, oid(DATA_OID));
+                    reader
+                        .next()
+                        .read_tagged(Tag::context(0), |reader| reader.read_bytes())
+                })
+            })
+        })
+        .unwrap();
+        let (bag_cert, friendly_name) = yasna::parse_der(&safe_contents, |reader| {
+            reader.read_sequence(|reader| {
+                reader.next().read_sequence(|reader| {
+                    assert_eq!(reader.next().read_oid()?, oid(CERT_BAG_OID));
+                    let bag_cert = reader.next().read_tagged(Tag::context(0), |reader| {
+                        reader.read_sequence(|reader| {
+                            assert_eq!(reader.next().read_oid()?, oid(X509_CERTIFICATE_OID));
+                            reader
+                                .next()
+                                .read_tagged(Tag::context(0), |reader| reader.read_bytes())
+                        })
+                    })?;
+                    let mut friendly_name = None;
+                    reader.next().read_set_of(|reader| {
+                        reader.read_sequence(|reader| {
+                            assert_eq!(reader.next().read_oid()?, oid(FRIENDLY_NAME_OID));
+                            reader.next().read_set_of(|reader| {
+                                friendly_name = Some(reader.read_bmp_string()?);
+                                Ok(())
+                            })
+                        })
+                    })?;
+                    Ok((bag_cert, friendly_name))
+                })
+            })
+        })
+        .unwrap();
+        assert_eq!(bag_cert, cert.0);
+        assert_eq!(friendly_name, Some(common_name(&cert).unwrap()));
+    }
+}
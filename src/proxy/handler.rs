@@ -1,10 +1,10 @@
-use super::mitm::RequestOrResponse;
+use super::{connector::Connector, mitm::RequestOrResponse};
 use http::{header, HeaderMap, Method, Request, Response, StatusCode, Uri};
 pub use hyper;
 use hyper::{body, Body};
 use moka::sync::Cache;
 use rand::seq::IteratorRandom;
-use reqwest::{Client, Error, Url};
+use reqwest::{Client, Error};
 use serde::{Deserialize, Serialize};
 use std::{future::Future, time::Duration};
 use tokio::task::JoinHandle;
@@ -17,12 +17,11 @@ pub struct DeviceCheckHandler {
 }
 
 impl DeviceCheckHandler {
-    pub fn new(proxy: Option<Url>) -> Result<Self, Error> {
+    pub(crate) fn new(connector: Connector) -> Result<Self, Error> {
         Ok(DeviceCheckHandler {
             client: Client::builder()
-                .proxy(reqwest::Proxy::custom(move |_| {
-                    proxy.as_ref().cloned().map_or(None, Some)
-                }))
+                .dns_resolver(connector.resolver())
+                .proxy(reqwest::Proxy::custom(move |url| connector.proxy_for(url)))
                 .build()?,
             cache: Cache::builder()
                 .max_capacity(u64::MAX)
@@ -0,0 +1,245 @@
+use crate::error::Error;
+use moka::sync::Cache;
+use rustls::AlertDescription;
+use serde::Serialize;
+
+/// Maximum number of hosts with recorded failures, beyond which the least used are forgotten.
+const MAX_HOSTS: u64 = 4096;
+
+/// Hosts whose clients reject the issued certificate, typically because they pin the upstream
+/// certificate.
+///
+/// A host is tunneled untouched once its TLS handshake has failed `threshold` times with a
+/// certificate alert from the client.
+pub struct LearnedPassthrough {
+    threshold: u32,
+    hosts: Cache<String, HostFailures>,
+}
+
+#[derive(Clone)]
+struct HostFailures {
+    failures: u32,
+    last_reason: String,
+}
+
+#[derive(Serialize)]
+struct LearnedHost {
+    host: String,
+    failures: u32,
+    last_reason: String,
+    passthrough: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LearnedHosts {
+    threshold: u32,
+    hosts: Vec<LearnedHost>,
+}
+
+impl LearnedPassthrough {
+    /// Learn hosts after `threshold` failed handshakes. A threshold of zero disables learning.
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            hosts: Cache::new(MAX_HOSTS),
+        }
+    }
+
+    /// Returns `true` if CONNECTs to `host` should be tunneled untouched.
+    pub fn contains(&self, host: &str) -> bool {
+        self.threshold > 0
+            && self
+                .hosts
+                .get(&normalize_host(host))
+                .is_some_and(|host| host.failures >= self.threshold)
+    }
+
+    /// Record a handshake with `host` that the client aborted for `reason`.
+    pub fn record_failure(&self, host: &str, reason: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        let host = normalize_host(host);
+        let entry = self
+            .hosts
+            .entry(host.clone())
+            .and_upsert_with(|entry| HostFailures {
+                failures: entry
+                    .map_or(0, |entry| entry.value().failures)
+                    .saturating_add(1),
+                last_reason: reason.to_owned(),
+            })
+            .into_value();
+
+        if entry.failures == self.threshold {
+            tracing::info!(
+                "Client rejected the certificate of {} {} times ({}), tunneling it from now on",
+                host,
+                entry.failures,
+                reason
+            );
+        } else {
+            tracing::debug!(
+                "Client rejected the certificate of {} ({}), {} of {} failures",
+                host,
+                reason,
+                entry.failures,
+                self.threshold
+            );
+        }
+    }
+
+    /// Forget `host`, or every host if `None`. Returns the number of hosts removed.
+    pub fn clear(&self, host: Option<&str>) -> usize {
+        match host {
+            Some(host) => self.hosts.remove(&normalize_host(host)).map_or(0, |_| 1),
+            None => self
+                .hosts
+                .iter()
+                .filter(|(host, _)| self.hosts.remove(host.as_str()).is_some())
+                .count(),
+        }
+    }
+
+    /// A snapshot of the recorded hosts.
+    pub(crate) fn list(&self) -> LearnedHosts {
+        let mut hosts = self
+            .hosts
+            .iter()
+            .map(|(host, failures)| LearnedHost {
+                host: host.as_ref().clone(),
+                failures: failures.failures,
+                last_reason: failures.last_reason.clone(),
+                passthrough: failures.failures >= self.threshold,
+            })
+            .collect::<Vec<_>>();
+        hosts.sort_by(|a, b| a.host.cmp(&b.host));
+
+        LearnedHosts {
+            threshold: self.threshold,
+            hosts,
+        }
+    }
+}
+
+/// Returns the reason if `err` is the client aborting the handshake because it does not trust
+/// the certificate.
+pub(crate) fn certificate_rejection(err: &Error) -> Option<String> {
+    let Error::IO(err) = err else {
+        return None;
+    };
+
+    match err.get_ref()?.downcast_ref::<rustls::Error>()? {
+        rustls::Error::AlertReceived(
+            alert @ (AlertDescription::BadCertificate
+            | AlertDescription::UnsupportedCertificate
+            | AlertDescription::CertificateRevoked
+            | AlertDescription::CertificateExpired
+            | AlertDescription::CertificateUnknown
+            | AlertDescription::UnknownCA),
+        ) => Some(format!("{alert:?}")),
+        _ => None,
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn alert(description: AlertDescription) -> Error {
+        let err = rustls::Error::AlertReceived(description);
+        Error::IO(io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    #[test]
+    fn hosts_are_learned_at_the_threshold() {
+        let learned = LearnedPassthrough::new(3);
+        learned.record_failure("api.example.com", "UnknownCA");
+        learned.record_failure("API.example.com.", "UnknownCA");
+        assert!(!learned.contains("api.example.com"));
+
+        learned.record_failure("api.example.com", "BadCertificate");
+        assert!(learned.contains("api.example.com"));
+        assert!(learned.contains("Api.Example.Com."));
+        assert!(!learned.contains("www.example.com"));
+
+        let list = learned.list();
+        assert_eq!(list.threshold, 3);
+        assert_eq!(list.hosts.len(), 1);
+        assert_eq!(list.hosts[0].host, "api.example.com");
+        assert_eq!(list.hosts[0].failures, 3);
+        assert_eq!(list.hosts[0].last_reason, "BadCertificate");
+        assert!(list.hosts[0].passthrough);
+    }
+
+    #[test]
+    fn zero_threshold_learns_nothing() {
+        let learned = LearnedPassthrough::new(0);
+        for _ in 0..5 {
+            learned.record_failure("api.example.com", "UnknownCA");
+        }
+        assert!(!learned.contains("api.example.com"));
+        assert!(learned.list().hosts.is_empty());
+    }
+
+    #[test]
+    fn only_certificate_alerts_are_rejections() {
+        for description in [
+            AlertDescription::BadCertificate,
+            AlertDescription::UnsupportedCertificate,
+            AlertDescription::CertificateRevoked,
+            AlertDescription::CertificateExpired,
+            AlertDescription::CertificateUnknown,
+            AlertDescription::UnknownCA,
+        ] {
+            assert_eq!(
+                certificate_rejection(&alert(description)),
+                Some(format!("{description:?}"))
+            );
+        }
+
+        for description in [
+            AlertDescription::HandshakeFailure,
+            AlertDescription::ProtocolVersion,
+            AlertDescription::DecryptError,
+            AlertDescription::CloseNotify,
+        ] {
+            assert_eq!(certificate_rejection(&alert(description)), None);
+        }
+
+        // Errors of our own side, or not from TLS at all
+        let err = Error::IO(io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::DecryptError,
+        ));
+        assert_eq!(certificate_rejection(&err), None);
+        let err = Error::IO(io::ErrorKind::ConnectionReset.into());
+        assert_eq!(certificate_rejection(&err), None);
+        let err = Error::Rustls(rustls::Error::AlertReceived(AlertDescription::UnknownCA));
+        assert_eq!(certificate_rejection(&err), None);
+    }
+
+    #[test]
+    fn hosts_are_cleared() {
+        let learned = LearnedPassthrough::new(1);
+        for host in ["a.example.com", "b.example.com", "c.example.com"] {
+            learned.record_failure(host, "UnknownCA");
+        }
+
+        assert_eq!(learned.clear(Some("A.example.com.")), 1);
+        assert_eq!(learned.clear(Some("a.example.com")), 0);
+        assert!(!learned.contains("a.example.com"));
+        assert!(learned.contains("b.example.com"));
+
+        assert_eq!(learned.clear(None), 2);
+        assert!(!learned.contains("b.example.com"));
+        assert!(!learned.contains("c.example.com"));
+        assert!(learned.list().hosts.is_empty());
+    }
+}
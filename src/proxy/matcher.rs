@@ -0,0 +1,287 @@
+use clap::ValueEnum;
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use std::{fmt, net::Ipv6Addr, str::FromStr, sync::Arc};
+
+/// A pattern matching host names.
+///
+/// - `*` matches every host.
+/// - `*.example.com` matches subdomains of `example.com`, but not `example.com` itself.
+/// - `.example.com` matches `example.com` and its subdomains.
+/// - Other patterns containing `*`, `?` or `[` are globs, e.g. `api*.example.com`.
+/// - `regex:<expr>` matches hosts against a regular expression, e.g. `regex:^(www|api)\.`.
+/// - Anything else matches the host exactly.
+///
+/// Matching is case-insensitive and ignores a trailing dot, and the brackets of IPv6 literals.
+#[derive(Clone, Debug)]
+pub enum HostPattern {
+    Any,
+    Exact(String),
+    Subdomain(String),
+    Domain(String),
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl HostPattern {
+    /// Returns `true` if `host` matches the pattern.
+    pub fn matches(&self, host: &str) -> bool {
+        let host = normalize_host(host);
+
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Exact(name) => host == *name,
+            HostPattern::Subdomain(name) => is_subdomain(&host, name),
+            HostPattern::Domain(name) => host == *name || is_subdomain(&host, name),
+            HostPattern::Glob(glob) => glob.is_match(&host),
+            HostPattern::Regex(regex) => regex.is_match(&host),
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(regex) = s.strip_prefix("regex:") {
+            return RegexBuilder::new(regex)
+                .case_insensitive(true)
+                .build()
+                .map(HostPattern::Regex)
+                .map_err(|e| format!("invalid host pattern '{s}': {e}"));
+        }
+
+        let pattern = normalize_host(s.trim());
+
+        if pattern.is_empty() {
+            return Err(format!("invalid host pattern '{s}'"));
+        }
+
+        if pattern == "*" {
+            return Ok(HostPattern::Any);
+        }
+
+        if let Some(name) = pattern.strip_prefix("*.").filter(|name| !is_glob(name)) {
+            return Ok(HostPattern::Subdomain(name.to_owned()));
+        }
+
+        if is_glob(&pattern) {
+            return GlobBuilder::new(&pattern)
+                .literal_separator(false)
+                .build()
+                .map(|glob| HostPattern::Glob(glob.compile_matcher()))
+                .map_err(|e| format!("invalid host pattern '{s}': {e}"));
+        }
+
+        match pattern.strip_prefix('.') {
+            Some("") => Err(format!("invalid host pattern '{s}'")),
+            Some(name) => Ok(HostPattern::Domain(name.to_owned())),
+            None => Ok(HostPattern::Exact(pattern)),
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Any => f.write_str("*"),
+            HostPattern::Exact(name) => f.write_str(name),
+            HostPattern::Subdomain(name) => write!(f, "*.{name}"),
+            HostPattern::Domain(name) => write!(f, ".{name}"),
+            HostPattern::Glob(glob) => f.write_str(glob.glob().glob()),
+            HostPattern::Regex(regex) => write!(f, "regex:{regex}"),
+        }
+    }
+}
+
+/// A value selected for hosts matching a pattern, parsed from `PATTERN=VALUE`.
+#[derive(Clone, Debug)]
+pub struct HostRule<T> {
+    pub pattern: HostPattern,
+    pub value: T,
+}
+
+impl<T> HostRule<T> {
+    /// Parse `PATTERN=VALUE`, where the pattern ends at the first `=`, since host names never
+    /// contain one but values such as proxy URLs may, with `parse_value` parsing the value.
+    pub fn parse_with<F>(s: &str, parse_value: F) -> Result<Self, String>
+    where
+        F: FnOnce(&str) -> Result<T, String>,
+    {
+        let (pattern, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected PATTERN=VALUE, got '{s}'"))?;
+
+        Ok(Self {
+            pattern: pattern.parse()?,
+            value: parse_value(value.trim())?,
+        })
+    }
+}
+
+impl<T: ValueEnum> FromStr for HostRule<T> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with(s, |value| T::from_str(value, true))
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for HostRule<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.value)
+    }
+}
+
+/// Ordered host rules, where the first matching rule wins. Cheap to clone.
+#[derive(Debug)]
+pub struct HostRules<T>(Arc<[HostRule<T>]>);
+
+impl<T> HostRules<T> {
+    /// Find the first rule matching `host`.
+    pub fn find(&self, host: &str) -> Option<&HostRule<T>> {
+        self.0.iter().find(|rule| rule.pattern.matches(host))
+    }
+
+    /// Get the value of the first rule matching `host`.
+    pub fn get(&self, host: &str) -> Option<&T> {
+        self.find(host).map(|rule| &rule.value)
+    }
+
+    /// The rules in order.
+    pub fn iter(&self) -> impl Iterator<Item = &HostRule<T>> {
+        self.0.iter()
+    }
+
+    /// Returns `true` if there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T> Clone for HostRules<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T> Default for HostRules<T> {
+    fn default() -> Self {
+        Self(Vec::new().into())
+    }
+}
+
+impl<T> From<Vec<HostRule<T>>> for HostRules<T> {
+    fn from(rules: Vec<HostRule<T>>) -> Self {
+        Self(rules.into())
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .filter(|ip| ip.parse::<Ipv6Addr>().is_ok())
+        .unwrap_or(host);
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+fn is_subdomain(host: &str, domain: &str) -> bool {
+    host.strip_suffix(domain)
+        .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> HostPattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn subdomain_and_domain_patterns() {
+        let subdomains = pattern("*.example.com");
+        assert!(subdomains.matches("www.example.com"));
+        assert!(subdomains.matches("a.b.example.com"));
+        assert!(!subdomains.matches("example.com"));
+        assert!(!subdomains.matches("badexample.com"));
+
+        let domain = pattern(".example.com");
+        assert!(domain.matches("www.example.com"));
+        assert!(domain.matches("example.com"));
+        assert!(!domain.matches("badexample.com"));
+
+        assert!(pattern("*").matches("anything.test"));
+        for invalid in ["", ".", "regex:("] {
+            assert!(invalid.parse::<HostPattern>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn glob_and_regex_patterns() {
+        let glob = pattern("api*.example.com");
+        assert!(matches!(glob, HostPattern::Glob(_)));
+        assert!(glob.matches("api.example.com"));
+        assert!(glob.matches("api2.eu.example.com"));
+        assert!(!glob.matches("www.example.com"));
+
+        let regex = pattern(r"regex:^(www|api)\.");
+        assert!(matches!(regex, HostPattern::Regex(_)));
+        assert!(regex.matches("www.example.com"));
+        assert!(regex.matches("API.example.org"));
+        assert!(!regex.matches("mail.example.com"));
+        // Not anchored unless the expression is
+        assert!(pattern("regex:example").matches("www.example.com"));
+    }
+
+    #[test]
+    fn hosts_are_normalized() {
+        let exact = pattern("Example.COM.");
+        assert!(exact.matches("example.com"));
+        assert!(exact.matches("EXAMPLE.com."));
+        assert!(!exact.matches("www.example.com"));
+
+        assert!(pattern("*.example.com").matches("WWW.Example.Com."));
+        assert!(pattern(".example.com").matches("example.com."));
+        assert!(pattern("api*.example.com").matches("API1.EXAMPLE.COM."));
+    }
+
+    #[test]
+    fn ip_literals() {
+        let ipv4 = pattern("10.0.0.1");
+        assert!(ipv4.matches("10.0.0.1"));
+        assert!(!ipv4.matches("10.0.0.10"));
+        assert!(pattern("10.0.0.*").matches("10.0.0.42"));
+
+        // As written in authorities, with or without brackets
+        for s in ["[::1]", "::1"] {
+            let ipv6 = pattern(s);
+            assert!(matches!(ipv6, HostPattern::Exact(_)), "{s}");
+            assert!(ipv6.matches("[::1]"), "{s}");
+            assert!(ipv6.matches("::1"), "{s}");
+            assert!(!ipv6.matches("[::2]"), "{s}");
+        }
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let rules = ["api.example.com=first", ".example.com=second", "*=third"]
+            .iter()
+            .map(|rule| HostRule::parse_with(rule, |value| Ok(value.to_owned())).unwrap())
+            .collect::<Vec<_>>();
+        let rules = HostRules::from(rules);
+
+        assert_eq!(rules.get("api.example.com").unwrap(), "first");
+        assert_eq!(rules.get("www.example.com").unwrap(), "second");
+        assert_eq!(rules.get("example.com").unwrap(), "second");
+        assert_eq!(rules.get("example.org").unwrap(), "third");
+        assert_eq!(rules.find("example.org").unwrap().to_string(), "*=third");
+
+        assert!(HostRules::<String>::default().get("example.com").is_none());
+    }
+}
@@ -1,14 +1,74 @@
 use super::handler::DeviceCheckHandler;
-use super::{ca::CertificateAuthority, client::HttpClient, rewind::Rewind};
+use super::{
+    ca::{CertificateAuthority, InvalidLeaf},
+    client::HttpClient,
+    connector::Connector,
+    export::ExportFormat,
+    learned::{self, LearnedPassthrough},
+    matcher::HostRules,
+    sniff::{self, Protocol},
+    timeout::{self, IdleTimeout, Timeouts},
+    upstream,
+    websocket::{self, WebSocketHook},
+};
+use crate::error::{Error, TimeoutKind, UpstreamFailure};
+use clap::ValueEnum;
 use http::uri::Authority;
 use http::StatusCode;
 use http::{header, uri::Scheme, Uri};
 use hyper::{server::conn::Http, service::service_fn, Body, Method, Request, Response};
-use std::sync::Arc;
-use tokio::io::AsyncReadExt;
+use moka::sync::Cache;
+use std::{fmt, net::IpAddr, sync::Arc, time::Duration};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{
+    rustls::{server::Acceptor, sign::CertifiedKey, CertificateError, ClientConfig, ServerConfig},
+    server::TlsStream,
+    LazyConfigAcceptor,
+};
+use tracing::Instrument;
+
+/// How long the verdict on the certificate of an upstream server is reused.
+const VERDICT_TTL: Duration = Duration::from_secs(5 * 60);
+/// Maximum number of upstream servers with a remembered verdict.
+const VERDICT_CAPACITY: u64 = 4096;
+
+/// Host name addressing the proxy itself in proxied requests.
+const PROXY_HOST: &str = "mitm";
+
+/// Header of gateway error responses carrying the ID of the failed flow.
+const FLOW_ID_HEADER: &str = "x-devicecheck-flow-id";
+
+/// Identifies a request in the logs and in the gateway error response answering it.
+#[derive(Clone, Copy, Debug)]
+struct FlowId(u64);
+
+impl FlowId {
+    fn new() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl fmt::Display for FlowId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Verdicts on upstream server certificates, per server name and port: nothing if the certificate
+/// is valid, or the invalid certificate presented to clients instead.
+#[derive(Clone)]
+pub(crate) struct UpstreamVerdicts(Cache<String, Option<Arc<CertifiedKey>>>);
+
+impl Default for UpstreamVerdicts {
+    fn default() -> Self {
+        Self(
+            Cache::builder()
+                .max_capacity(VERDICT_CAPACITY)
+                .time_to_live(VERDICT_TTL)
+                .build(),
+        )
+    }
+}
 
 /// Enum representing either an HTTP request or response.
 #[allow(dead_code)]
@@ -18,31 +78,76 @@ pub enum RequestOrResponse {
     Response(Response<Body>),
 }
 
+/// What to do with a CONNECT request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConnectAction {
+    /// Intercept TLS with an issued certificate
+    #[default]
+    Intercept,
+    /// Tunnel the connection to the target without looking into it
+    Passthrough,
+    /// Reject the CONNECT request with 403 Forbidden
+    Block,
+}
+
+impl fmt::Display for ConnectAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectAction::Intercept => f.write_str("intercept"),
+            ConnectAction::Passthrough => f.write_str("passthrough"),
+            ConnectAction::Block => f.write_str("block"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct MitmProxy {
     pub handler: DeviceCheckHandler,
     pub ca: Arc<CertificateAuthority>,
     pub client: HttpClient,
+    pub mimic_upstream_cert: bool,
+    pub verify_upstream_cert: bool,
+    pub upstream_verdicts: UpstreamVerdicts,
+    pub server_config: Arc<ServerConfig>,
+    pub websocket_hook: Option<Arc<dyn WebSocketHook>>,
+    pub connect_rules: HostRules<ConnectAction>,
+    pub learned_passthrough: Arc<LearnedPassthrough>,
+    pub connector: Connector,
+    /// TLS configuration for upstream connections driven by an HTTP/1.1 client
+    pub upstream_tls_config: Arc<ClientConfig>,
+    pub timeouts: Timeouts,
 }
 
 impl MitmProxy {
     pub(crate) async fn proxy(self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-        tracing::debug!("{req:?}");
-        if req.method() == Method::CONNECT {
-            Ok(self.process_connect(req))
-        } else {
-            self.process_request(normalize_request(req), Scheme::HTTP)
-                .await
+        let flow_id = FlowId::new();
+        let span = tracing::info_span!("flow", id = %flow_id);
+
+        async move {
+            tracing::debug!("{req:?}");
+            if req.method() == Method::CONNECT {
+                Ok(self.process_connect(req))
+            } else {
+                self.process_request(normalize_request(req), Scheme::HTTP, flow_id)
+                    .await
+            }
         }
+        .instrument(span)
+        .await
     }
 
     async fn process_request(
         self,
         mut req: Request<Body>,
         scheme: Scheme,
+        flow_id: FlowId,
     ) -> Result<Response<Body>, hyper::Error> {
         if req.uri().path().starts_with("/mitm/cert") {
-            return Ok(self.get_cert_res());
+            return Ok(self.get_cert_res(req.uri()));
+        }
+
+        if req.uri().path().starts_with("/mitm/passthrough") && is_proxy_endpoint(req.uri()) {
+            return Ok(self.get_learned_passthrough_res(&req));
         }
 
         if req.uri().path().starts_with("/auth/preauth") {
@@ -66,6 +171,26 @@ impl MitmProxy {
             req = Request::from_parts(parts, body);
         };
 
+        // reqwest cannot carry a protocol upgrade, so WebSocket handshakes are relayed directly
+        if websocket::is_upgrade_request(&req) {
+            let uri = req.uri().clone();
+            return match websocket::upgrade(
+                req,
+                self.websocket_hook.clone(),
+                &self.connector,
+                self.upstream_tls_config.clone(),
+            )
+            .await
+            {
+                Ok(res) => Ok(res),
+                Err(err) => {
+                    let err = err.into_upstream();
+                    tracing::warn!("WebSocket upgrade request to {} failed: {}", uri, err);
+                    Ok(gateway_error(&err, flow_id))
+                }
+            };
+        }
+
         // Fix VPN signature recognition
         {
             let headers = req.headers_mut();
@@ -82,11 +207,13 @@ impl MitmProxy {
         };
 
         // Send Http request
+        let uri = req.uri().clone();
         let mut res = match self.client.http(req).await {
             Ok(res) => res,
             Err(err) => {
-                tracing::debug!("Http proxy request failed: {err:?}");
-                bad_request()
+                let err = err.into_upstream();
+                tracing::warn!("Http proxy request to {} failed: {}", uri, err);
+                gateway_error(&err, flow_id)
             }
         };
 
@@ -101,77 +228,17 @@ impl MitmProxy {
     fn process_connect(self, mut req: Request<Body>) -> Response<Body> {
         match req.uri().authority().cloned() {
             Some(authority) => {
-                let fut = async move {
+                let action = self.connect_action(&authority);
+                if action == ConnectAction::Block {
+                    return forbidden();
+                }
+
+                tokio::spawn(async move {
                     match hyper::upgrade::on(&mut req).await {
-                        Ok(mut upgraded) => {
-                            let mut buffer = [0; 4];
-                            let bytes_read = match upgraded.read(&mut buffer).await {
-                                Ok(bytes_read) => bytes_read,
-                                Err(e) => {
-                                    tracing::error!(
-                                        "Failed to read from upgraded connection: {}",
-                                        e
-                                    );
-                                    return;
-                                }
-                            };
-
-                            let mut upgraded = Rewind::new_buffered(
-                                upgraded,
-                                bytes::Bytes::copy_from_slice(buffer[..bytes_read].as_ref()),
-                            );
-
-                            if buffer[..2] == *b"\x16\x03" {
-                                let server_config = self.ca.clone().gen_server_config();
-
-                                let stream =
-                                    match TlsAcceptor::from(server_config).accept(upgraded).await {
-                                        Ok(stream) => stream,
-                                        Err(e) => {
-                                            tracing::debug!(
-                                                "Failed to establish TLS connection: {}",
-                                                e
-                                            );
-                                            return;
-                                        }
-                                    };
-
-                                if let Err(e) =
-                                    self.serve_stream(stream, Scheme::HTTPS, authority).await
-                                {
-                                    if !e.to_string().starts_with("error shutting down connection")
-                                    {
-                                        tracing::error!("HTTPS connect error: {}", e);
-                                    }
-                                }
-
-                                return;
-                            } else {
-                                tracing::warn!(
-                                    "Unknown protocol, read '{:02X?}' from upgraded connection",
-                                    &buffer[..bytes_read]
-                                );
-                            }
-
-                            let mut server = match TcpStream::connect(authority.as_ref()).await {
-                                Ok(server) => server,
-                                Err(e) => {
-                                    tracing::error!("Failed to connect to {}: {}", authority, e);
-                                    return;
-                                }
-                            };
-
-                            if let Err(e) =
-                                tokio::io::copy_bidirectional(&mut upgraded, &mut server).await
-                            {
-                                tracing::error!("Failed to tunnel to {}: {}", authority, e);
-                            }
-                        }
+                        Ok(upgraded) => self.serve_tunnel(upgraded, authority, action).await,
                         Err(e) => tracing::error!("Upgrade error: {}", e),
-                    };
-                };
-
-                tokio::spawn(fut);
+                    }
+                });
 
                 Response::new(Body::empty())
             }
@@ -179,15 +246,146 @@ impl MitmProxy {
         }
     }
 
+    /// Serve a tunnel opened by the client to `authority` according to `action`. Blocked tunnels
+    /// are refused by the caller before they are opened.
+    pub(crate) async fn serve_tunnel<I>(self, io: I, authority: Authority, action: ConnectAction)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match action {
+            ConnectAction::Intercept => {
+                let (protocol, io) = match sniff::sniff(io, self.timeouts.server_first).await {
+                    Ok(sniffed) => sniffed,
+                    Err(e) => {
+                        tracing::error!("Failed to read from upgraded connection: {}", e);
+                        return;
+                    }
+                };
+                tracing::debug!("CONNECT {} protocol: {}", authority, protocol);
+                self.intercept(protocol, io, authority).await
+            }
+            ConnectAction::Passthrough => {
+                tunnel(io, &authority, &self.connector, &self.timeouts).await
+            }
+            ConnectAction::Block => {}
+        }
+    }
+
+    /// Intercept the tunnel to `authority` according to the `protocol` the client was sniffed to
+    /// speak: TLS is terminated with an issued certificate, plaintext HTTP is served directly, and
+    /// anything else is tunneled untouched.
+    pub(crate) async fn intercept<I>(self, protocol: Protocol, io: I, authority: Authority)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match protocol {
+            Protocol::Tls(hello) => {
+                // The client validates the certificate for the name it asked for
+                let server_name = hello
+                    .and_then(|hello| hello.server_name)
+                    .unwrap_or_else(|| authority.host().to_owned());
+                let invalid_key = if self.verify_upstream_cert {
+                    self.verify_upstream_cert(&authority, &server_name).await
+                } else {
+                    if self.mimic_upstream_cert {
+                        self.mimic_upstream_cert(&authority, &server_name).await;
+                    }
+                    None
+                };
+                let presented_invalid = invalid_key.is_some();
+
+                let stream = match self.accept_tls(io, authority.host(), invalid_key).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        tracing::debug!("Failed to establish TLS connection: {}", e);
+                        // Rejecting a deliberately invalid certificate says nothing about pinning
+                        if let Some(reason) =
+                            learned::certificate_rejection(&e).filter(|_| !presented_invalid)
+                        {
+                            self.learned_passthrough
+                                .record_failure(authority.host(), &reason);
+                        }
+                        return;
+                    }
+                };
+
+                let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                if let Err(e) = self
+                    .serve_stream(stream, Scheme::HTTPS, authority, http2)
+                    .await
+                {
+                    if !is_closed_by_client(&e) {
+                        tracing::error!("HTTPS connect error: {}", e);
+                    }
+                }
+            }
+            Protocol::Http1(_) | Protocol::Http2 => {
+                let http2 = matches!(protocol, Protocol::Http2);
+                if let Err(e) = self.serve_stream(io, Scheme::HTTP, authority, http2).await {
+                    if !is_closed_by_client(&e) {
+                        tracing::error!("HTTP connect error: {}", e);
+                    }
+                }
+            }
+            Protocol::Unknown(_) => {
+                tracing::warn!("Unknown protocol, tunneling to {}: {}", authority, protocol);
+                tunnel(io, &authority, &self.connector, &self.timeouts).await
+            }
+            Protocol::Ssh | Protocol::ServerFirst => {
+                tracing::debug!("Tunneling to {}: {}", authority, protocol);
+                tunnel(io, &authority, &self.connector, &self.timeouts).await
+            }
+        }
+    }
+
+    /// The proxy for the connection of a transparent client, sending the upstream requests for
+    /// `host`, the name the client asked for, to `ip`, the address it connected to.
+    pub(crate) fn with_destination(mut self, host: &str, ip: IpAddr) -> Result<Self, Error> {
+        self.client = self.client.with_destination(host, ip)?;
+        self.connector = self.connector.with_destination(host, ip);
+        Ok(self)
+    }
+
+    /// Select the action for a CONNECT to `authority` from the connect rules, logging the
+    /// decision. Hosts that match no rule are tunneled if their clients were learned to reject
+    /// the issued certificate, and intercepted otherwise.
+    pub(crate) fn connect_action(&self, authority: &Authority) -> ConnectAction {
+        match self.connect_rules.find(authority.host()) {
+            Some(rule) => {
+                tracing::info!("CONNECT {}: {} (rule {})", authority, rule.value, rule);
+                rule.value
+            }
+            None if self.learned_passthrough.contains(authority.host()) => {
+                let action = ConnectAction::Passthrough;
+                tracing::info!("CONNECT {}: {} (learned)", authority, action);
+                action
+            }
+            None => {
+                let action = ConnectAction::default();
+                tracing::info!("CONNECT {}: {} (default)", authority, action);
+                action
+            }
+        }
+    }
+
     async fn serve_stream<I>(
         self,
         stream: I,
         scheme: Scheme,
         authority: Authority,
+        http2: bool,
     ) -> Result<(), hyper::Error>
     where
         I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
+        let stream = IdleTimeout::new(
+            stream,
+            TimeoutKind::ClientIdle,
+            &self.timeouts,
+            format!("{scheme} connection to {authority}"),
+        );
+        let in_flight = stream.in_flight();
+
         let service = service_fn(|mut req| {
             if req.version() == hyper::Version::HTTP_10 || req.version() == hyper::Version::HTTP_11
             {
@@ -203,27 +401,210 @@ impl MitmProxy {
                 req = Request::from_parts(parts, body);
             };
 
-            self.clone().proxy(req)
+            let in_flight = in_flight.clone();
+            let connect = req.method() == Method::CONNECT;
+            let response = self.clone().proxy(req);
+            async move { in_flight.track(connect, response).await }
         });
 
         Http::new()
+            .http2_only(http2)
             .serve_connection(stream, service)
             .with_upgrades()
             .await
     }
 
-    fn get_cert_res(&self) -> Response<Body> {
+    /// Complete the TLS handshake with the client, presenting `certified_key` if given.
+    ///
+    /// Otherwise the certificate is resolved once the ClientHello has been read, so that issuing it
+    /// does not block the executor, and presented whether or not it is still cached by then.
+    /// Clients that send no SNI get a certificate for `default_host`.
+    pub(crate) async fn accept_tls<I>(
+        &self,
+        io: I,
+        default_host: &str,
+        certified_key: Option<Arc<CertifiedKey>>,
+    ) -> Result<TlsStream<I>, Error>
+    where
+        I: AsyncRead + AsyncWrite + Unpin,
+    {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), io).await?;
+
+        let certified_key = match certified_key {
+            Some(certified_key) => certified_key,
+            None => {
+                let server_name = start
+                    .client_hello()
+                    .server_name()
+                    .unwrap_or(default_host)
+                    .to_owned();
+                self.ca.resolve(&server_name).await?
+            }
+        };
+        let server_config =
+            CertificateAuthority::with_certified_key(&self.server_config, certified_key);
+
+        start.into_stream(server_config).await.map_err(Into::into)
+    }
+
+    /// Verify the certificate the upstream server at `authority` presents for `server_name` before
+    /// the client handshake, mimicking it if enabled.
+    ///
+    /// Returns a deliberately invalid certificate to present if verification fails, so that the
+    /// client rejects the connection as it would without the proxy. Upstream servers that cannot
+    /// be reached are left to fail the requests. Verdicts are reused for a while, so that every
+    /// connection to a host does not cost an extra handshake.
+    async fn verify_upstream_cert(
+        &self,
+        authority: &Authority,
+        server_name: &str,
+    ) -> Option<Arc<CertifiedKey>> {
+        let mut key = format!("{}:{}", server_name, authority.port_u16().unwrap_or(443));
+        // The server a transparent client connected to may not be the one the name resolves to
+        if let Some(ip) = self.connector.destination() {
+            key = format!("{key}@{ip}");
+        }
+        if let Some(verdict) = self.upstream_verdicts.0.get(&key) {
+            return verdict;
+        }
+
+        let tls_config = self.upstream_tls_config.clone();
+        let result =
+            upstream::verify_certificate(&self.connector, tls_config, authority, server_name).await;
+        let err = match result {
+            Ok(cert) => {
+                if self.mimic_upstream_cert && !self.ca.is_cached(server_name).await {
+                    if let Err(e) = self.ca.mimic(server_name, cert).await {
+                        tracing::debug!(
+                            "Failed to mimic upstream certificate of {}: {}",
+                            server_name,
+                            e
+                        );
+                    }
+                }
+                self.upstream_verdicts.0.insert(key, None);
+                return None;
+            }
+            Err(e) => e,
+        };
+
+        let Some(reason) = upstream::certificate_error(&err) else {
+            tracing::debug!(
+                "Failed to verify upstream certificate of {} at {}: {}",
+                server_name,
+                authority,
+                err
+            );
+            return None;
+        };
+
+        let (invalid, reason) = match reason {
+            CertificateError::Expired => (InvalidLeaf::Expired, "expired".to_owned()),
+            CertificateError::Other(e) => (InvalidLeaf::WrongName, e.to_string()),
+            reason => (InvalidLeaf::WrongName, format!("{reason:?}")),
+        };
+        tracing::warn!(
+            "Upstream certificate of {} at {} is invalid ({}), presenting an invalid certificate",
+            server_name,
+            authority,
+            reason
+        );
+
+        let ca = self.ca.clone();
+        let name = server_name.to_owned();
+        let certified_key =
+            match tokio::task::spawn_blocking(move || ca.invalid_certified_key(&name, invalid))
+                .await
+            {
+                Ok(certified_key) => certified_key,
+                Err(e) => {
+                    tracing::error!("Failed to issue an invalid certificate: {}", e);
+                    return None;
+                }
+            };
+        self.upstream_verdicts
+            .0
+            .insert(key, Some(certified_key.clone()));
+        Some(certified_key)
+    }
+
+    /// Issue a certificate mimicking the one the upstream server at `authority` presents for
+    /// `server_name`, unless a certificate for the name is already cached.
+    async fn mimic_upstream_cert(&self, authority: &Authority, server_name: &str) {
+        if self.ca.is_cached(server_name).await {
+            return;
+        }
+
+        let result =
+            match upstream::fetch_certificate(&self.connector, authority, server_name).await {
+                Ok(cert) => self.ca.mimic(server_name, cert).await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+
+        if let Err(e) = result {
+            tracing::debug!(
+                "Failed to mimic upstream certificate of {}: {}",
+                server_name,
+                e
+            );
+        }
+    }
+
+    /// Serve the root certificate in the format selected by the `format` query parameter.
+    fn get_cert_res(&self, uri: &Uri) -> Response<Body> {
+        let format = query_param(uri, "format").map_or(Ok(ExportFormat::default()), |format| {
+            ExportFormat::from_str(format, true)
+        });
+
+        let (format, cert) = match format
+            .ok()
+            .and_then(|format| Some((format, format.encode(self.ca.root_cert()).ok()?)))
+        {
+            Some(export) => export,
+            None => return bad_request(),
+        };
+
         Response::builder()
             .header(
                 http::header::CONTENT_DISPOSITION,
-                "attachment; filename=auth-mitm.crt",
+                format!("attachment; filename={}", format.file_name()),
             )
-            .header(http::header::CONTENT_TYPE, "application/octet-stream")
+            .header(http::header::CONTENT_TYPE, format.content_type())
             .status(http::StatusCode::OK)
-            .body(Body::from(self.ca.clone().get_cert()))
+            .body(Body::from(cert))
             .expect("Failed build response")
     }
 
+    /// List the hosts learned to be tunneled with `GET`, or forget them with `DELETE`, either all
+    /// of them or the one given by the `host` query parameter.
+    fn get_learned_passthrough_res(&self, req: &Request<Body>) -> Response<Body> {
+        let body = match *req.method() {
+            Method::GET => serde_json::to_vec_pretty(&self.learned_passthrough.list()),
+            Method::DELETE => {
+                let removed = self
+                    .learned_passthrough
+                    .clear(query_param(req.uri(), "host"));
+                serde_json::to_vec_pretty(&serde_json::json!({ "removed": removed }))
+            }
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(header::ALLOW, "GET, DELETE")
+                    .body(Body::empty())
+                    .expect("Failed to build response")
+            }
+        };
+
+        match body {
+            Ok(body) => Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .expect("Failed to build response"),
+            Err(_) => bad_request(),
+        }
+    }
+
     fn get_preauth_res(&self) -> Response<Body> {
         match self.handler.get_cookie_res() {
             Ok(res) => res,
@@ -232,6 +613,76 @@ impl MitmProxy {
     }
 }
 
+/// Returns `true` if `uri` addresses the proxy itself rather than an upstream server: requests sent
+/// to the proxy directly have no authority, and proxied ones are for the host [`PROXY_HOST`].
+fn is_proxy_endpoint(uri: &Uri) -> bool {
+    match uri.host() {
+        Some(host) => host.eq_ignore_ascii_case(PROXY_HOST),
+        None => true,
+    }
+}
+
+/// The value of the query parameter `name` of `uri`, if present.
+fn query_param<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+/// Tunnel `upgraded` to `authority` through `connector` without looking into the traffic, until
+/// either side closes or nothing was relayed for the tunnel idle timeout.
+async fn tunnel<I>(upgraded: I, authority: &Authority, connector: &Connector, timeouts: &Timeouts)
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let port = authority.port_u16().unwrap_or(443);
+    let mut server = match connector.connect(authority.host(), port, false).await {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!("Failed to connect to {}: {}", authority, e);
+            return;
+        }
+    };
+
+    let mut upgraded = IdleTimeout::new(
+        upgraded,
+        TimeoutKind::TunnelIdle,
+        timeouts,
+        format!("tunnel to {authority}"),
+    );
+    if let Err(e) = tokio::io::copy_bidirectional(&mut upgraded, &mut server).await {
+        // Idle tunnels are logged as they are closed
+        if !timeout::is_timeout(&e) {
+            tracing::error!("Failed to tunnel to {}: {}", authority, e);
+        }
+    }
+}
+
+fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::empty())
+        .expect("Failed to build response")
+}
+
+/// Answer a request whose upstream server failed with `err` with a JSON body naming the cause.
+fn gateway_error(err: &Error, flow_id: FlowId) -> Response<Body> {
+    let failure = err.upstream_failure().unwrap_or(UpstreamFailure::Protocol);
+    let body = serde_json::json!({
+        "error": failure.code(),
+        "message": err.to_string(),
+        "flow_id": flow_id.to_string(),
+    });
+
+    Response::builder()
+        .status(failure.status())
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(FLOW_ID_HEADER, flow_id.to_string())
+        .body(Body::from(body.to_string()))
+        .expect("Failed to build response")
+}
+
 fn bad_request() -> Response<Body> {
     Response::builder()
         .status(StatusCode::BAD_REQUEST)
@@ -239,9 +690,30 @@ fn bad_request() -> Response<Body> {
         .expect("Failed to build response")
 }
 
+/// Returns `true` if `err` only reports the client going away, e.g. closing the TCP connection
+/// without a TLS close_notify, or the connection being closed for sitting idle, which is logged
+/// as it happens.
+pub(crate) fn is_closed_by_client(err: &hyper::Error) -> bool {
+    if err
+        .to_string()
+        .starts_with("error shutting down connection")
+    {
+        return true;
+    }
+
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            return err.kind() == std::io::ErrorKind::UnexpectedEof || timeout::is_timeout(err);
+        }
+        source = err.source();
+    }
+
+    false
+}
+
 fn normalize_request<T>(mut req: Request<T>) -> Request<T> {
     // Hyper will automatically add a Host header if needed.
     req.headers_mut().remove(hyper::header::HOST);
-    *req.version_mut() = hyper::Version::HTTP_11;
     req
 }
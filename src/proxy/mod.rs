@@ -1,63 +1,281 @@
 mod ca;
+mod cert_store;
 mod client;
+mod connector;
+mod export;
 pub mod handler;
+mod keys;
+mod learned;
+mod matcher;
 mod mitm;
+mod resolver;
+mod reverse;
 mod rewind;
+mod sniff;
+mod socks;
+mod timeout;
+#[cfg(target_os = "linux")]
+mod transparent;
+mod upstream;
+mod upstream_tls;
+pub mod websocket;
 
 use self::client::HttpClient;
 use crate::error::Error;
-pub use ca::CertificateAuthority;
+pub use ca::{fingerprint, CertificateAuthority, KeyAlgorithm};
+pub use client::HttpVersionPolicy;
+use connector::Connector;
+pub use connector::Upstreams;
+pub use export::ExportFormat;
 use handler::DeviceCheckHandler;
 pub use hyper;
 use hyper::{
-    server::conn::AddrStream,
+    server::conn::{AddrIncoming, AddrStream},
     service::{make_service_fn, service_fn},
     Server,
 };
-use mitm::MitmProxy;
+pub use keys::{read_certs, read_private_key, write_private_file};
+use learned::LearnedPassthrough;
+pub use matcher::{HostPattern, HostRule, HostRules};
+pub use mitm::ConnectAction;
+use mitm::{MitmProxy, UpstreamVerdicts};
 use reqwest::Url;
-use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
+use resolver::Resolver;
+pub use resolver::{read_hosts_file, DnsServer, HostAddrs};
+pub use reverse::{Origin, ReverseProxy};
+pub use socks::SocksAuth;
+use std::{convert::Infallible, future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+pub use timeout::Timeouts;
+use timeout::{IdleIncoming, IdleTimeout};
+use tokio::net::{TcpListener, TcpStream};
 use typed_builder::TypedBuilder;
+pub use upstream_tls::{SpkiPins, UpstreamTls};
+use websocket::WebSocketHook;
 
 #[derive(TypedBuilder)]
 pub struct Proxy {
     /// The address to listen on.
     pub listen_addr: SocketAddr,
 
-    /// Upstream proxy
+    /// Upstream proxy for hosts that match no route
     pub proxy: Option<Url>,
 
+    /// Upstreams to connect through, per host, tried in order until one is reachable.
+    #[builder(default)]
+    pub routes: HostRules<Upstreams>,
+
+    /// How often to check whether the upstream proxies are reachable, zero disables the checks.
+    #[builder(default = Duration::from_secs(30))]
+    pub health_check_interval: Duration,
+
+    /// Addresses to resolve host names to instead of asking the DNS server, per host.
+    #[builder(default)]
+    pub hosts: HostRules<HostAddrs>,
+
+    /// The DNS server to resolve upstream host names with.
+    #[builder(default)]
+    pub dns_server: DnsServer,
+
+    /// The longest time to cache resolved addresses for, zero disables the cache.
+    #[builder(default = Duration::from_secs(60))]
+    pub dns_cache_ttl: Duration,
+
+    /// Limits on upstream connects and requests, and on idle client connections and tunnels.
+    #[builder(default)]
+    pub timeouts: Timeouts,
+
+    /// How upstream server certificates are verified, and the client certificate presented.
+    #[builder(default)]
+    pub upstream_tls: UpstreamTls,
+
     /// The certificate authority to use.
     pub ca: Arc<CertificateAuthority>,
+
+    /// Mimic the upstream server certificate when issuing leaf certificates.
+    #[builder(default)]
+    pub mimic_upstream_cert: bool,
+
+    /// Verify the upstream server certificate before completing the client handshake, presenting
+    /// an invalid certificate to the client if verification fails.
+    #[builder(default)]
+    pub verify_upstream_cert: bool,
+
+    /// ALPN protocols offered to clients of intercepted TLS connections, in order of preference.
+    #[builder(default)]
+    pub alpn_protocols: Vec<String>,
+
+    /// HTTP version used for upstream requests, per host.
+    #[builder(default)]
+    pub http_versions: HostRules<HttpVersionPolicy>,
+
+    /// Hook invoked for every relayed WebSocket message.
+    #[builder(default)]
+    pub websocket_hook: Option<Arc<dyn WebSocketHook>>,
+
+    /// Whether to intercept, tunnel or block CONNECT requests, per host.
+    #[builder(default)]
+    pub connect_rules: HostRules<ConnectAction>,
+
+    /// Number of certificate alerts from clients after which a host is tunneled untouched, zero
+    /// disables learning.
+    #[builder(default)]
+    pub passthrough_after: u32,
+
+    /// The address to accept SOCKS5 and SOCKS4a clients on.
+    #[builder(default)]
+    pub socks_listen_addr: Option<SocketAddr>,
+
+    /// Credentials required from SOCKS5 clients.
+    #[builder(default)]
+    pub socks_auth: Option<SocksAuth>,
+
+    /// The address to accept connections redirected by the firewall on, Linux only.
+    #[builder(default)]
+    pub transparent_listen_addr: Option<SocketAddr>,
+
+    /// A listener forwarding every request to a fixed origin.
+    #[builder(default)]
+    pub reverse: Option<ReverseProxy>,
 }
 
 impl Proxy {
     pub async fn start<F: Future<Output = ()>>(self, shutdown_signal: F) -> Result<(), Error> {
-        let proxy = self.proxy;
-        let client = HttpClient::new(proxy.clone())?;
-        let handler = DeviceCheckHandler::new(proxy)?;
-        let make_service = make_service_fn(move |_conn: &AddrStream| {
-            let ca = Arc::clone(&self.ca);
-            let client = client.clone();
-            let handler = handler.clone();
+        let resolver = Resolver::new(self.hosts, self.dns_server, self.dns_cache_ttl)?;
+        let connector = Connector::new(self.routes, self.proxy, resolver, self.timeouts)?;
+        let server_config = Arc::clone(&self.ca).gen_server_config(
+            self.alpn_protocols
+                .into_iter()
+                .map(String::into_bytes)
+                .collect(),
+        )?;
+        let mitm_proxy = MitmProxy {
+            ca: self.ca,
+            client: HttpClient::new(
+                connector.clone(),
+                self.http_versions,
+                &self.upstream_tls,
+                self.timeouts,
+            )?,
+            handler: DeviceCheckHandler::new(connector.clone())?,
+            mimic_upstream_cert: self.mimic_upstream_cert,
+            verify_upstream_cert: self.verify_upstream_cert,
+            upstream_verdicts: UpstreamVerdicts::default(),
+            server_config,
+            websocket_hook: self.websocket_hook,
+            connect_rules: self.connect_rules,
+            learned_passthrough: Arc::new(LearnedPassthrough::new(self.passthrough_after)),
+            connector: connector.clone(),
+            upstream_tls_config: Arc::new(self.upstream_tls.client_config(&[b"http/1.1"])?),
+            timeouts: self.timeouts,
+        };
+
+        let health_check = (!self.health_check_interval.is_zero())
+            .then(|| tokio::spawn(connector.check_health(self.health_check_interval)));
+
+        let socks = match self.socks_listen_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                let auth = self.socks_auth.map(Arc::new);
+                Some(tokio::spawn(socks::serve(
+                    listener,
+                    auth,
+                    mitm_proxy.clone(),
+                )))
+            }
+            None => None,
+        };
+
+        let transparent = match self.transparent_listen_addr {
+            #[cfg(target_os = "linux")]
+            Some(addr) => {
+                let listener = transparent::bind(addr)?;
+                Some(tokio::spawn(transparent::serve(
+                    listener,
+                    mitm_proxy.clone(),
+                )))
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(Error::IO(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "transparent proxying is only supported on Linux",
+                )))
+            }
+            None => None,
+        };
+
+        let reverse = match self.reverse {
+            Some(reverse) => {
+                let listener = TcpListener::bind(reverse.listen_addr).await?;
+                Some(tokio::spawn(reverse::serve(
+                    listener,
+                    reverse,
+                    mitm_proxy.clone(),
+                )))
+            }
+            None => None,
+        };
+
+        let make_service = make_service_fn(move |conn: &IdleTimeout<AddrStream>| {
+            let mitm_proxy = mitm_proxy.clone();
+            let in_flight = conn.in_flight();
             async move {
                 Ok::<_, Infallible>(service_fn(move |req| {
-                    let mitm_proxy = MitmProxy {
-                        ca: Arc::clone(&ca),
-                        client: client.clone(),
-                        handler: handler.clone(),
-                    };
-                    mitm_proxy.proxy(req)
+                    let in_flight = in_flight.clone();
+                    let connect = req.method() == hyper::Method::CONNECT;
+                    let response = mitm_proxy.clone().proxy(req);
+                    async move { in_flight.track(connect, response).await }
                 }))
             }
         });
 
-        Server::bind(&self.listen_addr)
+        let incoming = AddrIncoming::bind(&self.listen_addr)?;
+        Server::builder(IdleIncoming::new(incoming, self.timeouts))
             .http1_preserve_header_case(true)
             .http1_title_case_headers(true)
             .serve(make_service)
             .with_graceful_shutdown(shutdown_signal)
-            .await
-            .map_err(Into::into)
+            .await?;
+
+        for task in [socks, transparent, reverse, health_check]
+            .into_iter()
+            .flatten()
+        {
+            task.abort();
+        }
+
+        Ok(())
     }
 }
+
+/// How long accepting connections pauses after an error such as running out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Accept the next connection on `listener`, logging failures as accepting a `kind` connection.
+///
+/// Like hyper's `AddrIncoming`, errors of a single connection are skipped, while other errors
+/// pause accepting for a moment rather than retrying in a busy loop.
+pub(crate) async fn accept(listener: &TcpListener, kind: &str) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) if is_connection_error(&e) => {
+                tracing::debug!("Failed to accept {} connection: {}", kind, e);
+            }
+            Err(e) => {
+                tracing::error!("Failed to accept {} connection: {}", kind, e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Returns `true` if `e` concerns only the connection being accepted.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
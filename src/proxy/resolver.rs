@@ -0,0 +1,629 @@
+use super::matcher::{HostPattern, HostRule, HostRules};
+use crate::error::Error;
+use hyper::client::connect::dns::Name;
+use moka::{sync::Cache, Expiry};
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    Url,
+};
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// How long a query to a DNS server may take.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of cached host names.
+const CACHE_CAPACITY: u64 = 4096;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Addresses a host name resolves to, parsed from a comma separated list.
+#[derive(Clone, Debug)]
+pub struct HostAddrs(Arc<[IpAddr]>);
+
+impl FromStr for HostAddrs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|addr| {
+                addr.trim()
+                    .parse()
+                    .map_err(|_| format!("invalid IP address '{addr}'"))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|addrs| Self(addrs.into()))
+    }
+}
+
+impl fmt::Display for HostAddrs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, addr) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            addr.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for HostRule<HostAddrs> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HostRule::parse_with(s, HostAddrs::from_str)
+    }
+}
+
+/// Read the entries of a hosts file, with lines of an IP address followed by host names.
+pub fn read_hosts_file(path: &Path) -> Result<Vec<HostRule<HostAddrs>>, Error> {
+    let hosts = std::fs::read_to_string(path).map_err(|source| Error::ReadFile {
+        path: path.to_owned(),
+        source,
+    })?;
+
+    let mut rules = Vec::new();
+    for line in hosts.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(addr) = fields.next() else {
+            continue;
+        };
+        let Ok(addr) = addr.parse::<IpAddr>() else {
+            tracing::warn!("Ignoring invalid line in {}: {}", path.display(), line);
+            continue;
+        };
+
+        for name in fields {
+            match name.parse::<HostPattern>() {
+                Ok(pattern) => rules.push(HostRule {
+                    pattern,
+                    value: HostAddrs(Arc::new([addr])),
+                }),
+                Err(e) => tracing::warn!("Ignoring host in {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    Ok(rules)
+}
+
+/// The DNS server upstream host names are resolved with.
+///
+/// Parsed from `system`, `tcp://IP[:PORT]` for DNS over TCP, or an `https` URL for DNS over HTTPS.
+#[derive(Clone, Debug, Default)]
+pub enum DnsServer {
+    /// The resolver of the operating system
+    #[default]
+    System,
+    /// DNS over TCP
+    Tcp(SocketAddr),
+    /// DNS over HTTPS, RFC 8484
+    Https(Url),
+}
+
+impl FromStr for DnsServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "system" {
+            return Ok(DnsServer::System);
+        }
+
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            let addr = addr.trim_end_matches('/');
+            return addr
+                .parse::<SocketAddr>()
+                .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                .map(DnsServer::Tcp)
+                .map_err(|_| format!("invalid DNS server address '{addr}'"));
+        }
+
+        match Url::parse(s) {
+            Ok(url) if url.scheme() == "https" => Ok(DnsServer::Https(url)),
+            _ => Err(format!(
+                "invalid DNS server '{s}', expected system, tcp://IP[:PORT] or an https URL"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DnsServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsServer::System => f.write_str("system"),
+            DnsServer::Tcp(addr) => write!(f, "tcp://{addr}"),
+            DnsServer::Https(url) => f.write_str(url.as_str()),
+        }
+    }
+}
+
+/// Resolves upstream host names: first to the destination of the connection the resolver was
+/// made for, if any, then from the hosts overrides, then from the cache, and then by querying the
+/// DNS server. Shared by the HTTP clients and the tunnels. Cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Resolver {
+    inner: Arc<Inner>,
+    /// The host name a transparent client asked for and the address it connected to
+    destination: Option<(Arc<str>, IpAddr)>,
+}
+
+struct Inner {
+    hosts: HostRules<HostAddrs>,
+    server: DnsServer,
+    /// Client for DNS over HTTPS
+    client: reqwest::Client,
+    cache: Option<Cache<String, Resolved>>,
+    /// How long answers of the system resolver are cached, and the most answers of DNS servers
+    /// are
+    max_ttl: Duration,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("hosts", &self.inner.hosts.iter().count())
+            .field("server", &self.inner.server)
+            .field("max_ttl", &self.inner.max_ttl)
+            .field("destination", &self.destination)
+            .finish()
+    }
+}
+
+#[derive(Clone)]
+struct Resolved {
+    addrs: Arc<[IpAddr]>,
+    ttl: Duration,
+}
+
+struct ResolvedExpiry;
+
+impl Expiry<String, Resolved> for ResolvedExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &Resolved,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+}
+
+impl Resolver {
+    /// A resolver answering from `hosts` first, first match wins, and querying `server` for the
+    /// other names. Answers are cached for at most `max_ttl`, zero disables the cache.
+    pub(crate) fn new(
+        hosts: HostRules<HostAddrs>,
+        server: DnsServer,
+        max_ttl: Duration,
+    ) -> Result<Self, Error> {
+        let cache = (!max_ttl.is_zero()).then(|| {
+            Cache::builder()
+                .max_capacity(CACHE_CAPACITY)
+                .expire_after(ResolvedExpiry)
+                .build()
+        });
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                hosts,
+                server,
+                client: reqwest::Client::builder().timeout(QUERY_TIMEOUT).build()?,
+                cache,
+                max_ttl,
+            }),
+            destination: None,
+        })
+    }
+
+    /// A resolver for the connection of a transparent client, resolving `host`, the name the
+    /// client asked for, to `ip`, the address it connected to, so that its requests go where the
+    /// client sent them. Other resolvers are left unchanged.
+    pub(crate) fn with_destination(&self, host: &str, ip: IpAddr) -> Self {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let destination = host
+            .parse::<IpAddr>()
+            .is_err()
+            .then(|| (host.trim_end_matches('.').to_ascii_lowercase().into(), ip));
+
+        Self {
+            inner: self.inner.clone(),
+            destination,
+        }
+    }
+
+    /// The address of the transparent connection this resolver was made for, if any.
+    pub(crate) fn destination(&self) -> Option<IpAddr> {
+        self.destination.as_ref().map(|(_, ip)| *ip)
+    }
+
+    /// Resolve `host`, which may be an IP address, optionally bracketed.
+    pub(crate) async fn lookup(&self, host: &str) -> io::Result<Arc<[IpAddr]>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.lookup_addrs(host).await.map_err(|source| {
+            let kind = source.kind();
+            let host = host.to_owned();
+            io::Error::new(kind, Error::Dns { host, source })
+        })
+    }
+
+    async fn lookup_addrs(&self, host: &str) -> io::Result<Arc<[IpAddr]>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Arc::new([ip]));
+        }
+
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some((_, ip)) = self
+            .destination
+            .as_ref()
+            .filter(|(host, _)| **host == *name)
+        {
+            return Ok(Arc::new([*ip]));
+        }
+
+        if let Some(addrs) = self.inner.hosts.get(host) {
+            return Ok(addrs.0.clone());
+        }
+
+        if let Some(resolved) = self.inner.cache.as_ref().and_then(|cache| cache.get(&name)) {
+            return Ok(resolved.addrs);
+        }
+
+        let (addrs, ttl) = match &self.inner.server {
+            DnsServer::System => {
+                let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                    .await?
+                    .map(|addr| addr.ip())
+                    .collect::<Vec<_>>();
+                (addrs, self.inner.max_ttl)
+            }
+            server => self.query_server(server, &name).await?,
+        };
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{host} has no addresses"),
+            ));
+        }
+
+        let addrs = Arc::<[IpAddr]>::from(addrs);
+        tracing::debug!("Resolved {} to {:?}, valid for {:?}", name, addrs, ttl);
+        if let Some(cache) = &self.inner.cache {
+            let ttl = ttl.min(self.inner.max_ttl);
+            if !ttl.is_zero() {
+                cache.insert(
+                    name,
+                    Resolved {
+                        addrs: addrs.clone(),
+                        ttl,
+                    },
+                );
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// Connect to `host:port`, trying its addresses in turn.
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for ip in self.lookup(host).await?.iter() {
+            match TcpStream::connect(SocketAddr::new(*ip, port)).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::other("no addresses")))
+    }
+
+    /// Query the IPv4 and IPv6 addresses of `name`, returning them with the lowest TTL of the
+    /// answers. A failed query of one family is ignored if the other one found addresses.
+    async fn query_server(
+        &self,
+        server: &DnsServer,
+        name: &str,
+    ) -> io::Result<(Vec<IpAddr>, Duration)> {
+        let (v4, v6) = tokio::join!(
+            self.query(server, name, TYPE_A),
+            self.query(server, name, TYPE_AAAA)
+        );
+
+        let (addrs, ttl) = match (v4, v6) {
+            (Ok((mut addrs, v4_ttl)), Ok((v6, v6_ttl))) => {
+                addrs.extend(v6);
+                (addrs, v4_ttl.min(v6_ttl))
+            }
+            (Ok(answer), Err(e)) | (Err(e), Ok(answer)) if !answer.0.is_empty() => {
+                tracing::debug!("Using the addresses of {} found despite: {}", name, e);
+                answer
+            }
+            (Err(e), _) | (_, Err(e)) => return Err(e),
+        };
+        Ok((addrs, Duration::from_secs(ttl.into())))
+    }
+
+    async fn query(
+        &self,
+        server: &DnsServer,
+        name: &str,
+        record_type: u16,
+    ) -> io::Result<(Vec<IpAddr>, u32)> {
+        // DNS over HTTPS uses ID 0 to be cache friendly
+        let id = match server {
+            DnsServer::Https(_) => 0,
+            _ => rand::random(),
+        };
+        let query = build_query(id, name, record_type)?;
+
+        let response = tokio::time::timeout(QUERY_TIMEOUT, async {
+            match server {
+                DnsServer::System => unreachable!("system resolver is not queried"),
+                DnsServer::Tcp(addr) => query_tcp(*addr, &query).await,
+                DnsServer::Https(url) => self.query_https(url, query).await,
+            }
+        })
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("DNS query to {server} timed out"),
+            )
+        })??;
+
+        parse_response(&response, id, record_type)
+    }
+
+    async fn query_https(&self, url: &Url, query: Vec<u8>) -> io::Result<Vec<u8>> {
+        let response = self
+            .inner
+            .client
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, DNS_MESSAGE)
+            .header(reqwest::header::ACCEPT, DNS_MESSAGE)
+            .body(query)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io::Error::other)?;
+
+        response
+            .bytes()
+            .await
+            .map(|bytes| bytes.to_vec())
+            .map_err(io::Error::other)
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.lookup(name.as_str()).await?;
+            // The port is set by the client
+            let addrs = addrs
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, 0))
+                .collect::<Vec<_>>();
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Send `query` to the DNS server at `addr` over TCP, where messages are prefixed by their length.
+async fn query_tcp(addr: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_u16(query.len() as u16).await?;
+    stream.write_all(query).await?;
+
+    let len = stream.read_u16().await?;
+    let mut response = vec![0; len as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// Build a recursive query for the records of `record_type` of `name`.
+fn build_query(id: u16, name: &str, record_type: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid host name '{name}'"),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Parse the addresses of `record_type` out of a response, with their lowest TTL. Other records,
+/// such as the CNAMEs leading to them, are skipped.
+fn parse_response(response: &[u8], id: u16, record_type: u16) -> io::Result<(Vec<IpAddr>, u32)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS response");
+
+    if response.len() < 12 || u16::from_be_bytes([response[0], response[1]]) != id {
+        return Err(malformed());
+    }
+    // Name errors have no addresses, other errors fail the lookup
+    match response[3] & 0x0F {
+        0 | 3 => {}
+        rcode => {
+            return Err(io::Error::other(format!(
+                "DNS server answered with error code {rcode}"
+            )))
+        }
+    }
+
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+    let mut pos = 12;
+
+    for _ in 0..questions {
+        pos = skip_name(response, pos).ok_or_else(malformed)? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = skip_name(response, pos).ok_or_else(malformed)?;
+        let header = response.get(pos..pos + 10).ok_or_else(malformed)?;
+        let kind = u16::from_be_bytes([header[0], header[1]]);
+        let record_ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let data = response
+            .get(pos + 10..pos + 10 + len)
+            .ok_or_else(malformed)?;
+        pos += 10 + len;
+
+        let addr = match (kind, data.len()) {
+            (TYPE_A, 4) if kind == record_type => {
+                IpAddr::V4(Ipv4Addr::from([data[0], data[1], data[2], data[3]]))
+            }
+            (TYPE_AAAA, 16) if kind == record_type => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => continue,
+        };
+        addrs.push(addr);
+        ttl = ttl.min(record_ttl);
+    }
+
+    Ok((addrs, ttl))
+}
+
+/// Returns the position after the possibly compressed name starting at `pos`, or `None` if the
+/// name is truncated or malformed.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len & 0xC0 {
+            0 if len == 0 => return Some(pos + 1),
+            0 => pos += 1 + len as usize,
+            // A pointer ends the name
+            0xC0 => return message.get(pos + 1).map(|_| pos + 2),
+            // Extended label types are not in use
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+
+    /// A response to the A query of `example.com` with `rcode` and `answers`, which are appended
+    /// as they are.
+    fn response(rcode: u8, answer_count: u16, answers: &[u8]) -> Vec<u8> {
+        let mut response = build_query(ID, "example.com", TYPE_A).unwrap();
+        response[2] = 0x81;
+        response[3] = 0x80 | rcode;
+        response[6..8].copy_from_slice(&answer_count.to_be_bytes());
+        response.extend_from_slice(answers);
+        response
+    }
+
+    /// A resource record named by `name`, with `ttl` and `data`.
+    fn record(name: &[u8], kind: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut record = name.to_vec();
+        record.extend_from_slice(&kind.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn query_encodes_labels() {
+        let query = build_query(ID, "example.com", TYPE_AAAA).unwrap();
+        assert_eq!(
+            query,
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+              \x07example\x03com\x00\x00\x1c\x00\x01"
+        );
+
+        assert!(build_query(ID, "example..com", TYPE_A).is_err());
+        assert!(build_query(ID, &format!("{}.com", "a".repeat(64)), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn response_follows_compressed_cname_chain() {
+        // www.example.com, pointing back to example.com in the question at offset 12
+        let cname = b"\x03www\xc0\x0c";
+        let mut answers = record(b"\xc0\x0c", 5, 300, cname);
+        // The CNAME target starts after the first answer's name, header and data length
+        let target = 29 + 2 + 10;
+        answers.extend(record(&[0xC0, target], TYPE_A, 60, &[93, 184, 216, 34]));
+        answers.extend(record(&[0xC0, target], TYPE_A, 120, &[1, 2, 3, 4]));
+        answers.extend(record(&[0xC0, target], TYPE_AAAA, 30, &[0; 16]));
+
+        let (addrs, ttl) = parse_response(&response(0, 4, &answers), ID, TYPE_A).unwrap();
+        assert_eq!(
+            addrs,
+            [IpAddr::from([93, 184, 216, 34]), IpAddr::from([1, 2, 3, 4])]
+        );
+        assert_eq!(ttl, 60);
+    }
+
+    #[test]
+    fn response_errors_by_rcode() {
+        let (addrs, _) = parse_response(&response(3, 0, &[]), ID, TYPE_A).unwrap();
+        assert!(addrs.is_empty());
+
+        let err = parse_response(&response(2, 0, &[]), ID, TYPE_A).unwrap_err();
+        assert!(err.to_string().contains("error code 2"));
+    }
+
+    #[test]
+    fn malformed_responses_are_rejected() {
+        let answers = record(b"\xc0\x0c", TYPE_A, 60, &[1, 2, 3, 4]);
+        let complete = response(0, 1, &answers);
+        assert!(parse_response(&complete, ID, TYPE_A).is_ok());
+
+        // Another query's answer
+        assert!(parse_response(&complete, ID + 1, TYPE_A).is_err());
+        // Truncated anywhere within the answer, or even the header
+        for len in [4, complete.len() - answers.len() + 1, complete.len() - 1] {
+            let err = parse_response(&complete[..len], ID, TYPE_A).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        // More answers than the response holds
+        assert!(parse_response(&response(0, 2, &answers), ID, TYPE_A).is_err());
+    }
+
+    #[test]
+    fn names_are_skipped() {
+        assert_eq!(skip_name(b"\x07example\x03com\x00", 0), Some(13));
+        assert_eq!(skip_name(b"\x03www\xc0\x0c", 0), Some(6));
+        // Truncated label, truncated pointer, extended label type
+        assert_eq!(skip_name(b"\x07exam", 0), None);
+        assert_eq!(skip_name(b"\x03www\xc0", 0), None);
+        assert_eq!(skip_name(b"\x41abc\x00", 0), None);
+    }
+}
@@ -0,0 +1,170 @@
+use super::{
+    accept,
+    mitm::{is_closed_by_client, MitmProxy},
+    timeout::IdleTimeout,
+};
+use crate::error::TimeoutKind;
+use http::{
+    uri::{Authority, Scheme},
+    Method, StatusCode, Uri,
+};
+use hyper::{server::conn::Http, service::service_fn, Body, Request, Response};
+use std::{fmt, net::SocketAddr, str::FromStr};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+/// The origin a reverse proxy forwards every request to, parsed from `http[s]://host[:port]`.
+#[derive(Clone, Debug)]
+pub struct Origin {
+    scheme: Scheme,
+    authority: Authority,
+}
+
+impl FromStr for Origin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri = Uri::from_str(s).map_err(|e| format!("invalid origin '{s}': {e}"))?;
+
+        let scheme = match uri.scheme() {
+            Some(scheme) if *scheme == Scheme::HTTP || *scheme == Scheme::HTTPS => scheme.clone(),
+            _ => return Err(format!("origin '{s}' must start with http:// or https://")),
+        };
+        let authority = uri
+            .authority()
+            .cloned()
+            .ok_or_else(|| format!("origin '{s}' has no host"))?;
+        if uri.path() != "/" || uri.query().is_some() {
+            return Err(format!("origin '{s}' must not have a path or query"));
+        }
+
+        Ok(Self { scheme, authority })
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.authority)
+    }
+}
+
+/// A listener that fronts a fixed origin, so clients can reach it without proxy settings.
+#[derive(Clone, Debug)]
+pub struct ReverseProxy {
+    /// The address to listen on.
+    pub listen_addr: SocketAddr,
+
+    /// The origin every request is forwarded to.
+    pub origin: Origin,
+
+    /// Terminate TLS with a certificate issued by the CA for the name clients connect to.
+    pub tls: bool,
+}
+
+/// Accept clients on `listener` and forward their requests to the origin through `mitm_proxy`,
+/// with the same handler, logging and rewrites as proxied requests.
+pub(crate) async fn serve(listener: TcpListener, reverse: ReverseProxy, mitm_proxy: MitmProxy) {
+    loop {
+        let (stream, peer) = accept(&listener, "reverse proxy").await;
+
+        let origin = reverse.origin.clone();
+        let mitm_proxy = mitm_proxy.clone();
+        tokio::spawn(async move {
+            let result = if reverse.tls {
+                accept_tls(stream, peer, origin, mitm_proxy).await
+            } else {
+                serve_connection(stream, peer, origin, false, mitm_proxy).await
+            };
+
+            if let Err(e) = result {
+                if !is_closed_by_client(&e) {
+                    tracing::error!("Reverse proxy connection from {} failed: {}", peer, e);
+                }
+            }
+        });
+    }
+}
+
+/// Complete the TLS handshake with the client before serving it. Clients that send no SNI
+/// connected by address, so they get a certificate for the local address.
+async fn accept_tls(
+    stream: TcpStream,
+    peer: SocketAddr,
+    origin: Origin,
+    mitm_proxy: MitmProxy,
+) -> Result<(), hyper::Error> {
+    let local_ip = match stream.local_addr() {
+        Ok(addr) => addr.ip().to_canonical().to_string(),
+        Err(e) => {
+            tracing::debug!("Failed to get local address: {}", e);
+            return Ok(());
+        }
+    };
+
+    let stream = match mitm_proxy.accept_tls(stream, &local_ip, None).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            tracing::debug!("Failed to establish TLS connection: {}", e);
+            return Ok(());
+        }
+    };
+
+    let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+    serve_connection(stream, peer, origin, http2, mitm_proxy).await
+}
+
+async fn serve_connection<I>(
+    stream: I,
+    peer: SocketAddr,
+    origin: Origin,
+    http2: bool,
+    mitm_proxy: MitmProxy,
+) -> Result<(), hyper::Error>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stream = IdleTimeout::new(
+        stream,
+        TimeoutKind::ClientIdle,
+        &mitm_proxy.timeouts,
+        format!("reverse proxy connection from {peer}"),
+    );
+    let in_flight = stream.in_flight();
+
+    let service = service_fn(move |mut req: Request<Body>| {
+        let origin = origin.clone();
+        let mitm_proxy = mitm_proxy.clone();
+        let in_flight = in_flight.clone();
+        async move {
+            // Not a forward proxy, the request can only be for the origin
+            if req.method() == Method::CONNECT {
+                return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+            }
+
+            let mut uri = req.uri().clone().into_parts();
+            uri.scheme = Some(origin.scheme);
+            uri.authority = Some(origin.authority);
+            match Uri::from_parts(uri) {
+                Ok(uri) => *req.uri_mut() = uri,
+                Err(_) => return Ok(status(StatusCode::BAD_REQUEST)),
+            }
+
+            in_flight.track(false, mitm_proxy.proxy(req)).await
+        }
+    });
+
+    Http::new()
+        .http2_only(http2)
+        .serve_connection(stream, service)
+        .with_upgrades()
+        .await
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .expect("Failed to build response")
+}
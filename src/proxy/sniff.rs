@@ -0,0 +1,499 @@
+use super::rewind::Rewind;
+use bytes::BytesMut;
+use std::{fmt, io, time::Duration};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long to wait for the rest of the bytes needed to classify the stream.
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum number of bytes buffered while sniffing. A ClientHello fits in a single record, whose
+/// payload is at most 16 KiB.
+const MAX_SNIFF_LEN: usize = 5 + (1 << 14) + 256;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_ALPN: u16 = 0x0010;
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const SSH_PREFIX: &[u8] = b"SSH-";
+
+/// The protocol a client speaks inside a CONNECT tunnel.
+#[derive(Debug)]
+pub(crate) enum Protocol {
+    /// TLS, with the fields of the ClientHello if it could be parsed
+    Tls(Option<ClientHello>),
+    /// Plaintext HTTP/1.x, with the Host header of the first request if it has one
+    Http1(Option<String>),
+    /// Plaintext HTTP/2 with prior knowledge
+    Http2,
+    /// SSH
+    Ssh,
+    /// The client sent nothing, the server probably speaks first
+    ServerFirst,
+    /// Anything else, with the first bytes read
+    Unknown(Vec<u8>),
+}
+
+/// Fields of a TLS ClientHello.
+#[derive(Debug, Default)]
+pub(crate) struct ClientHello {
+    pub(crate) server_name: Option<String>,
+    pub(crate) alpn_protocols: Vec<String>,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tls(Some(hello)) => write!(
+                f,
+                "TLS (SNI {}, ALPN [{}])",
+                hello.server_name.as_deref().unwrap_or("none"),
+                hello.alpn_protocols.join(", ")
+            ),
+            Protocol::Tls(None) => f.write_str("TLS (malformed ClientHello)"),
+            Protocol::Http1(Some(host)) => write!(f, "HTTP/1.x (Host {host})"),
+            Protocol::Http1(None) => f.write_str("HTTP/1.x"),
+            Protocol::Http2 => f.write_str("HTTP/2 (prior knowledge)"),
+            Protocol::Ssh => f.write_str("SSH"),
+            Protocol::ServerFirst => f.write_str("none, the server speaks first"),
+            Protocol::Unknown(prefix) => write!(f, "unknown ({prefix:02X?})"),
+        }
+    }
+}
+
+/// Read from `io` until the protocol of the stream can be told, and return it with `io` rewound
+/// to the start of the stream.
+///
+/// Protocols where the server speaks first, such as SMTP, are told once the client has sent
+/// nothing for `server_first`. Zero waits for the client indefinitely.
+pub(crate) async fn sniff<I>(mut io: I, server_first: Duration) -> io::Result<(Protocol, Rewind<I>)>
+where
+    I: AsyncRead + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(1024);
+
+    let first_read = io.read_buf(&mut buf);
+    let first_read = if server_first.is_zero() {
+        Ok(first_read.await)
+    } else {
+        tokio::time::timeout(server_first, first_read).await
+    };
+
+    let protocol = match first_read {
+        Err(_) => Protocol::ServerFirst,
+        Ok(read) => {
+            read?;
+            let sniffed = tokio::time::timeout(SNIFF_TIMEOUT, async {
+                loop {
+                    if let Some(protocol) = classify(&buf, false) {
+                        return Ok::<_, io::Error>(protocol);
+                    }
+                    if buf.len() >= MAX_SNIFF_LEN || io.read_buf(&mut buf).await? == 0 {
+                        return Ok(classify(&buf, true).expect("complete classification"));
+                    }
+                }
+            })
+            .await;
+
+            match sniffed {
+                Ok(protocol) => protocol?,
+                Err(_) => classify(&buf, true).expect("complete classification"),
+            }
+        }
+    };
+
+    Ok((protocol, Rewind::new_buffered(io, buf.freeze())))
+}
+
+/// Classify `buf`, returning `None` if more bytes are needed. When `complete` is set no more bytes
+/// will arrive, so the best guess is returned.
+fn classify(buf: &[u8], complete: bool) -> Option<Protocol> {
+    if buf.is_empty() {
+        return Some(Protocol::ServerFirst);
+    }
+
+    if buf[0] == TLS_HANDSHAKE {
+        return match client_hello(buf) {
+            Ok(hello) => Some(Protocol::Tls(Some(hello))),
+            Err(ParseError::Incomplete) if !complete => None,
+            Err(_) => Some(Protocol::Tls(None)),
+        };
+    }
+
+    for (prefix, protocol) in [(H2_PREFACE, Protocol::Http2), (SSH_PREFIX, Protocol::Ssh)] {
+        match starts_with(buf, prefix) {
+            Some(true) => return Some(protocol),
+            None if !complete => return None,
+            _ => {}
+        }
+    }
+
+    match request_line(buf) {
+        Some(true) => match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => Some(Protocol::Http1(host_header(&buf[..end + 2]))),
+            None if !complete => None,
+            None => Some(Protocol::Http1(host_header(buf))),
+        },
+        None if !complete => None,
+        _ => Some(Protocol::Unknown(buf[..buf.len().min(16)].to_vec())),
+    }
+}
+
+/// Returns whether `buf` starts with `prefix`, or `None` if `buf` is a shorter part of it.
+fn starts_with(buf: &[u8], prefix: &[u8]) -> Option<bool> {
+    if buf.len() < prefix.len() {
+        return if prefix.starts_with(buf) {
+            None
+        } else {
+            Some(false)
+        };
+    }
+    Some(buf.starts_with(prefix))
+}
+
+/// Returns whether `buf` starts with an HTTP/1.x request line, or `None` if the line is not
+/// complete yet.
+fn request_line(buf: &[u8]) -> Option<bool> {
+    let method_len = buf
+        .iter()
+        .position(|&b| !b.is_ascii_uppercase())
+        .unwrap_or(buf.len());
+    if method_len == 0 || method_len > 16 || (method_len < buf.len() && buf[method_len] != b' ') {
+        return Some(false);
+    }
+
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        return if buf.len() < 8192 { None } else { Some(false) };
+    };
+
+    let line = &buf[..end];
+    Some(line.ends_with(b" HTTP/1.1") || line.ends_with(b" HTTP/1.0"))
+}
+
+/// The value of the Host header among the complete lines of the request head `head`.
+fn host_header(head: &[u8]) -> Option<String> {
+    head.split(|&b| b == b'\n')
+        .skip(1)
+        .filter_map(|line| line.strip_suffix(b"\r"))
+        .find_map(|line| {
+            let colon = line.iter().position(|&b| b == b':')?;
+            let (name, value) = line.split_at(colon);
+            name.eq_ignore_ascii_case(b"host")
+                .then(|| String::from_utf8_lossy(&value[1..]).trim().to_owned())
+        })
+        .filter(|host| !host.is_empty())
+}
+
+/// Why a ClientHello could not be parsed.
+enum ParseError {
+    /// More bytes are needed
+    Incomplete,
+    /// The bytes are not a ClientHello
+    Malformed,
+}
+
+/// Parse the ClientHello at the start of `buf`, which may span several handshake records.
+fn client_hello(buf: &[u8]) -> Result<ClientHello, ParseError> {
+    let mut handshake = Vec::new();
+    let mut records = buf;
+
+    loop {
+        if records.len() < 5 {
+            return Err(ParseError::Incomplete);
+        }
+        if records[0] != TLS_HANDSHAKE {
+            return Err(ParseError::Malformed);
+        }
+        let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        if records.len() < 5 + len {
+            return Err(ParseError::Incomplete);
+        }
+        handshake.extend_from_slice(&records[5..5 + len]);
+        records = &records[5 + len..];
+
+        if handshake.len() >= 4 {
+            if handshake[0] != TLS_CLIENT_HELLO {
+                return Err(ParseError::Malformed);
+            }
+            let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + len {
+                return parse_client_hello(&handshake[4..4 + len]).ok_or(ParseError::Malformed);
+            }
+        }
+    }
+}
+
+/// Parse the body of a ClientHello handshake message.
+fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+    let mut body = Reader(body);
+    body.take(2 + 32)?; // legacy_version, random
+    body.vec8()?; // legacy_session_id
+    body.vec16()?; // cipher_suites
+    body.vec8()?; // legacy_compression_methods
+
+    let mut hello = ClientHello::default();
+    // Extensions are optional before TLS 1.3
+    let Some(extensions) = body.vec16() else {
+        return Some(hello);
+    };
+
+    let mut extensions = Reader(extensions);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = Reader(extensions.vec16()?);
+
+        match kind {
+            EXT_SERVER_NAME => {
+                let mut names = Reader(data.vec16()?);
+                while !names.0.is_empty() {
+                    let name_type = names.u8()?;
+                    let name = names.vec16()?;
+                    // host_name
+                    if name_type == 0 {
+                        hello.server_name = Some(String::from_utf8_lossy(name).into_owned());
+                    }
+                }
+            }
+            EXT_ALPN => {
+                let mut protocols = Reader(data.vec16()?);
+                while !protocols.0.is_empty() {
+                    let protocol = protocols.vec8()?;
+                    hello
+                        .alpn_protocols
+                        .push(String::from_utf8_lossy(protocol).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(hello)
+}
+
+/// Reads big-endian TLS fields from a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    /// `data` prefixed by its length in `width` bytes.
+    fn vec(width: usize, data: &[u8]) -> Vec<u8> {
+        let mut vec = (data.len() as u32).to_be_bytes()[4 - width..].to_vec();
+        vec.extend_from_slice(data);
+        vec
+    }
+
+    fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut extension = kind.to_be_bytes().to_vec();
+        extension.extend(vec(2, data));
+        extension
+    }
+
+    /// The body of a ClientHello with `extensions`, which are appended as they are.
+    fn client_hello_body(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend([0; 32]);
+        body.extend(vec(1, &[]));
+        body.extend(vec(2, &[0x13, 0x01]));
+        body.extend(vec(1, &[0]));
+        body.extend(vec(2, extensions));
+        body
+    }
+
+    /// A ClientHello handshake message for `example.com` offering h2 and http/1.1.
+    fn client_hello_message() -> Vec<u8> {
+        let mut names = vec![0];
+        names.extend(vec(2, b"example.com"));
+        let mut extensions = extension(EXT_SERVER_NAME, &vec(2, &names));
+        let mut protocols = vec(1, b"h2");
+        protocols.extend(vec(1, b"http/1.1"));
+        extensions.extend(extension(EXT_ALPN, &vec(2, &protocols)));
+
+        let mut message = vec![TLS_CLIENT_HELLO];
+        message.extend(vec(3, &client_hello_body(&extensions)));
+        message
+    }
+
+    /// `fragments` of a handshake message, each in its own record.
+    fn records(fragments: &[&[u8]]) -> Vec<u8> {
+        fragments
+            .iter()
+            .flat_map(|fragment| {
+                let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+                record.extend(vec(2, fragment));
+                record
+            })
+            .collect()
+    }
+
+    fn assert_example_hello(protocol: Option<Protocol>) {
+        match protocol {
+            Some(Protocol::Tls(Some(hello))) => {
+                assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+                assert_eq!(hello.alpn_protocols, ["h2", "http/1.1"]);
+            }
+            protocol => panic!("classified as {protocol:?}"),
+        }
+    }
+
+    fn http1_host(buf: &[u8], complete: bool) -> Option<Option<String>> {
+        match classify(buf, complete)? {
+            Protocol::Http1(host) => Some(host),
+            protocol => panic!("classified as {protocol}"),
+        }
+    }
+
+    #[test]
+    fn http1_host_header() {
+        let head = b"GET / HTTP/1.1\r\nAccept: */*\r\nhost: example.com:8080\r\n\r\n";
+        assert_eq!(
+            http1_host(head, false),
+            Some(Some("example.com:8080".to_owned()))
+        );
+    }
+
+    #[test]
+    fn http1_waits_for_headers() {
+        assert_eq!(http1_host(b"GET / HTTP/1.1\r\nHost: exa", false), None);
+        assert_eq!(http1_host(b"GET / HTTP/1.1\r\nHost: exa", true), Some(None));
+        assert_eq!(
+            http1_host(b"GET / HTTP/1.0\r\n\r\n", false),
+            Some(None),
+            "no Host header"
+        );
+    }
+
+    #[test]
+    fn client_hello_sni_and_alpn() {
+        let buf = records(&[&client_hello_message()]);
+        assert_example_hello(classify(&buf, false));
+    }
+
+    #[test]
+    fn client_hello_across_records() {
+        let message = client_hello_message();
+        // Split within the handshake header, and within the body
+        for at in [2, 40] {
+            let buf = records(&[&message[..at], &message[at..]]);
+            assert_example_hello(classify(&buf, false));
+        }
+    }
+
+    #[test]
+    fn client_hello_waits_for_records() {
+        let message = client_hello_message();
+        let buf = records(&[&message[..40], &message[40..]]);
+        for len in [1, 4, 5, 20, 45, 50, buf.len() - 1] {
+            assert!(classify(&buf[..len], false).is_none(), "{len} bytes");
+            assert!(
+                matches!(classify(&buf[..len], true), Some(Protocol::Tls(None))),
+                "{len} bytes, complete"
+            );
+        }
+        // A record longer than any ClientHello waits until the sniffing limit
+        assert!(classify(b"\x16\x03\x01\xff\xff\x01", false).is_none());
+    }
+
+    #[tokio::test]
+    async fn client_hello_across_reads() {
+        let message = client_hello_message();
+        let buf = records(&[&message[..40], &message[40..]]);
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+
+        let sniffer = tokio::spawn(sniff(server, Duration::from_secs(5)));
+        client.write_all(&buf[..50]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(&buf[50..]).await.unwrap();
+
+        let (protocol, _) = sniffer.await.unwrap().unwrap();
+        assert_example_hello(Some(protocol));
+    }
+
+    #[test]
+    fn malformed_client_hello_is_rejected() {
+        let truncated = [
+            // Extension longer than the extensions
+            extension(EXT_SERVER_NAME, b"\x00\x0e\x00\x00\x0bexample.com")[..8].to_vec(),
+            // Server name list longer than the extension
+            extension(EXT_SERVER_NAME, b"\xff\xff\x00\x00\x0bexample.com"),
+            // Host name longer than the server name list
+            extension(EXT_SERVER_NAME, b"\x00\x04\x00\xff\xffexample.com"),
+            // Protocol longer than the protocol list
+            extension(EXT_ALPN, b"\x00\x03\xffh2"),
+            // Half an extension type
+            vec![0x00],
+        ];
+        for extensions in truncated {
+            let body = client_hello_body(&extensions);
+            assert!(parse_client_hello(&body).is_none(), "{extensions:02X?}");
+
+            let mut message = vec![TLS_CLIENT_HELLO];
+            message.extend(vec(3, &body));
+            assert!(matches!(
+                classify(&records(&[&message]), false),
+                Some(Protocol::Tls(None))
+            ));
+        }
+
+        // Another handshake message, or a record of another type
+        assert!(matches!(
+            classify(&records(&[b"\x02\x00\x00\x00"]), false),
+            Some(Protocol::Tls(None))
+        ));
+        let mut buf = records(&[&client_hello_message()[..10]]);
+        buf.extend(b"\x17\x03\x03\x00\x00");
+        assert!(matches!(classify(&buf, false), Some(Protocol::Tls(None))));
+    }
+
+    #[test]
+    fn http2_preface_and_ssh() {
+        assert!(matches!(classify(H2_PREFACE, false), Some(Protocol::Http2)));
+        assert!(classify(&H2_PREFACE[..10], false).is_none());
+        assert!(matches!(
+            classify(b"SSH-2.0-OpenSSH_9.6\r\n", false),
+            Some(Protocol::Ssh)
+        ));
+        assert!(classify(b"SS", false).is_none());
+        assert!(matches!(
+            classify(b"SS", true),
+            Some(Protocol::Unknown(prefix)) if prefix == b"SS"
+        ));
+    }
+
+    #[tokio::test]
+    async fn silent_client_is_server_first() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let (protocol, _) = sniff(server, Duration::from_millis(20)).await.unwrap();
+        assert!(matches!(protocol, Protocol::ServerFirst));
+    }
+}
@@ -0,0 +1,418 @@
+use super::{
+    accept,
+    mitm::{ConnectAction, MitmProxy},
+};
+use http::uri::Authority;
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// How long a client may take to negotiate the tunnel.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SOCKS4: u8 = 0x04;
+const SOCKS5: u8 = 0x05;
+const CMD_CONNECT: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+const PASSWORD_VERSION: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+
+/// Username and password required from SOCKS5 clients, parsed from `USER:PASS`.
+#[derive(Clone, Debug)]
+pub struct SocksAuth {
+    username: String,
+    password: String,
+}
+
+impl FromStr for SocksAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((username, password))
+                if !username.is_empty() && username.len() <= 255 && password.len() <= 255 =>
+            {
+                Ok(Self {
+                    username: username.to_owned(),
+                    password: password.to_owned(),
+                })
+            }
+            _ => Err("expected USER:PASS, each at most 255 bytes".to_owned()),
+        }
+    }
+}
+
+/// Accept SOCKS5 and SOCKS4a clients on `listener`, and hand their CONNECTs to `mitm_proxy`.
+///
+/// SOCKS4 has no password, so it is refused when `auth` is set.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    auth: Option<Arc<SocksAuth>>,
+    mitm_proxy: MitmProxy,
+) {
+    loop {
+        let (stream, peer) = accept(&listener, "SOCKS").await;
+
+        let auth = auth.clone();
+        let mitm_proxy = mitm_proxy.clone();
+        tokio::spawn(async move {
+            let handshake = tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                handshake(stream, auth.as_deref(), |authority| {
+                    mitm_proxy.connect_action(authority)
+                }),
+            )
+            .await;
+
+            match handshake {
+                Ok(Ok(Some((stream, authority, action)))) => {
+                    mitm_proxy.serve_tunnel(stream, authority, action).await
+                }
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => tracing::debug!("SOCKS handshake with {} failed: {}", peer, e),
+                Err(_) => tracing::debug!("SOCKS handshake with {} timed out", peer),
+            }
+        });
+    }
+}
+
+/// Negotiate a tunnel with a SOCKS client, returning the stream and its target, or `None` if the
+/// request was refused. The action for the target is selected by `connect_action`.
+async fn handshake<S>(
+    mut stream: S,
+    auth: Option<&SocksAuth>,
+    connect_action: impl Fn(&Authority) -> ConnectAction,
+) -> io::Result<Option<(S, Authority, ConnectAction)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let accepted = match stream.read_u8().await? {
+        SOCKS5 => socks5_handshake(&mut stream, auth, &connect_action).await?,
+        SOCKS4 => socks4_handshake(&mut stream, auth, &connect_action).await?,
+        version => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported SOCKS version {version}"),
+            ))
+        }
+    };
+
+    Ok(accepted.map(|(authority, action)| (stream, authority, action)))
+}
+
+async fn socks5_handshake<S>(
+    stream: &mut S,
+    auth: Option<&SocksAuth>,
+    connect_action: &impl Fn(&Authority) -> ConnectAction,
+) -> io::Result<Option<(Authority, ConnectAction)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Method selection
+    let len = stream.read_u8().await?;
+    let mut methods = vec![0; len as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = match auth {
+        Some(_) => METHOD_PASSWORD,
+        None => METHOD_NO_AUTH,
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[SOCKS5, METHOD_NONE_ACCEPTABLE]).await?;
+        return Ok(None);
+    }
+    stream.write_all(&[SOCKS5, method]).await?;
+
+    // Username/password authentication, RFC 1929
+    if let Some(auth) = auth {
+        let version = stream.read_u8().await?;
+        let username = read_vec8(stream).await?;
+        let password = read_vec8(stream).await?;
+        let valid = version == PASSWORD_VERSION
+            && username == auth.username.as_bytes()
+            && password == auth.password.as_bytes();
+
+        stream
+            .write_all(&[PASSWORD_VERSION, if valid { 0x00 } else { 0x01 }])
+            .await?;
+        if !valid {
+            tracing::debug!("SOCKS5 authentication failed");
+            return Ok(None);
+        }
+    }
+
+    // Request
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [_, command, _, address_type] = header;
+
+    let host = match address_type {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        ATYP_DOMAIN => String::from_utf8_lossy(&read_vec8(stream).await?).into_owned(),
+        _ => {
+            socks5_reply(stream, REP_ADDRESS_NOT_SUPPORTED).await?;
+            return Ok(None);
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if command != CMD_CONNECT {
+        socks5_reply(stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        return Ok(None);
+    }
+
+    let Ok(authority) = Authority::try_from(format!("{host}:{port}")) else {
+        socks5_reply(stream, REP_ADDRESS_NOT_SUPPORTED).await?;
+        return Ok(None);
+    };
+
+    tracing::debug!("SOCKS5 CONNECT {}", authority);
+    let action = connect_action(&authority);
+    if action == ConnectAction::Block {
+        socks5_reply(stream, REP_NOT_ALLOWED).await?;
+        return Ok(None);
+    }
+
+    socks5_reply(stream, REP_SUCCEEDED).await?;
+    Ok(Some((authority, action)))
+}
+
+async fn socks4_handshake<S>(
+    stream: &mut S,
+    auth: Option<&SocksAuth>,
+    connect_action: &impl Fn(&Authority) -> ConnectAction,
+) -> io::Result<Option<(Authority, ConnectAction)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let command = stream.read_u8().await?;
+    let port = stream.read_u16().await?;
+    let mut ip = [0; 4];
+    stream.read_exact(&mut ip).await?;
+    let _user_id = read_cstr(stream).await?;
+
+    // SOCKS4a: 0.0.0.x with x != 0 means that the host name follows the user ID
+    let host = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+        String::from_utf8_lossy(&read_cstr(stream).await?).into_owned()
+    } else {
+        Ipv4Addr::from(ip).to_string()
+    };
+
+    if auth.is_some() || command != CMD_CONNECT {
+        socks4_reply(stream, SOCKS4_REJECTED).await?;
+        return Ok(None);
+    }
+
+    let Ok(authority) = Authority::try_from(format!("{host}:{port}")) else {
+        socks4_reply(stream, SOCKS4_REJECTED).await?;
+        return Ok(None);
+    };
+
+    tracing::debug!("SOCKS4 CONNECT {}", authority);
+    let action = connect_action(&authority);
+    if action == ConnectAction::Block {
+        socks4_reply(stream, SOCKS4_REJECTED).await?;
+        return Ok(None);
+    }
+
+    socks4_reply(stream, SOCKS4_GRANTED).await?;
+    Ok(Some((authority, action)))
+}
+
+/// Reply to a SOCKS5 request. The bound address is not meaningful for an intercepting proxy, so
+/// it is always reported as `0.0.0.0:0`.
+async fn socks5_reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: u8) -> io::Result<()> {
+    stream
+        .write_all(&[SOCKS5, reply, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+async fn socks4_reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: u8) -> io::Result<()> {
+    stream.write_all(&[0x00, reply, 0, 0, 0, 0, 0, 0]).await
+}
+
+/// Read a byte string prefixed with its length as one byte.
+async fn read_vec8<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let len = stream.read_u8().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Read a NUL terminated byte string of at most 255 bytes.
+async fn read_cstr<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(buf),
+            _ if buf.len() == 255 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "SOCKS4 string too long",
+                ))
+            }
+            b => buf.push(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the handshake of `request`, returning the reply and the negotiated target, if any.
+    /// Refused clients are disconnected, so the reply is everything the server wrote.
+    async fn negotiate(
+        request: &[u8],
+        auth: Option<&str>,
+    ) -> (Vec<u8>, Option<(Authority, ConnectAction)>) {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+
+        let auth = auth.map(|auth| auth.parse::<SocksAuth>().unwrap());
+        let accepted = handshake(server, auth.as_ref(), |_| ConnectAction::Intercept)
+            .await
+            .unwrap();
+        let accepted = accepted.map(|(_, authority, action)| (authority, action));
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        (reply, accepted)
+    }
+
+    /// A SOCKS5 CONNECT with `command`, `address_type` and `address`, to port 443.
+    fn socks5_request(command: u8, address_type: u8, address: &[u8]) -> Vec<u8> {
+        let mut request = vec![SOCKS5, 1, METHOD_NO_AUTH, SOCKS5, command, 0, address_type];
+        request.extend_from_slice(address);
+        request.extend_from_slice(&443u16.to_be_bytes());
+        request
+    }
+
+    const SUCCEEDED: [u8; 12] = [
+        SOCKS5,
+        METHOD_NO_AUTH,
+        SOCKS5,
+        REP_SUCCEEDED,
+        0,
+        ATYP_IPV4,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+
+    #[tokio::test]
+    async fn socks5_connect_without_auth() {
+        let mut ipv6 = [0; 16];
+        ipv6[15] = 1;
+        let requests = [
+            (ATYP_IPV4, vec![10, 0, 0, 1], "10.0.0.1:443"),
+            (ATYP_DOMAIN, b"\x0bexample.com".to_vec(), "example.com:443"),
+            (ATYP_IPV6, ipv6.to_vec(), "[::1]:443"),
+        ];
+
+        for (address_type, address, target) in requests {
+            let request = socks5_request(CMD_CONNECT, address_type, &address);
+            let (reply, accepted) = negotiate(&request, None).await;
+            assert_eq!(reply, SUCCEEDED);
+            let (authority, action) = accepted.unwrap();
+            assert_eq!(authority.as_str(), target);
+            assert_eq!(action, ConnectAction::Intercept);
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_password() {
+        let request = |password: &[u8]| {
+            let mut request = vec![SOCKS5, 2, METHOD_NO_AUTH, METHOD_PASSWORD, PASSWORD_VERSION];
+            request.extend_from_slice(b"\x04user");
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            request.extend(&socks5_request(CMD_CONNECT, ATYP_IPV4, &[10, 0, 0, 1])[3..]);
+            request
+        };
+
+        let (reply, accepted) = negotiate(&request(b"pass"), Some("user:pass")).await;
+        assert_eq!(
+            reply[..4],
+            [SOCKS5, METHOD_PASSWORD, PASSWORD_VERSION, 0x00]
+        );
+        assert_eq!(reply[4..6], [SOCKS5, REP_SUCCEEDED]);
+        assert!(accepted.is_some());
+
+        let (reply, accepted) = negotiate(&request(b"wrong"), Some("user:pass")).await;
+        assert_eq!(reply, [SOCKS5, METHOD_PASSWORD, PASSWORD_VERSION, 0x01]);
+        assert!(accepted.is_none());
+
+        // A client offering no password is turned away before authenticating
+        let request = socks5_request(CMD_CONNECT, ATYP_IPV4, &[10, 0, 0, 1]);
+        let (reply, accepted) = negotiate(&request, Some("user:pass")).await;
+        assert_eq!(reply, [SOCKS5, METHOD_NONE_ACCEPTABLE]);
+        assert!(accepted.is_none());
+    }
+
+    #[tokio::test]
+    async fn socks5_unsupported_command() {
+        // BIND
+        let request = socks5_request(0x02, ATYP_IPV4, &[10, 0, 0, 1]);
+        let (reply, accepted) = negotiate(&request, None).await;
+        assert_eq!(
+            reply[..4],
+            [SOCKS5, METHOD_NO_AUTH, SOCKS5, REP_COMMAND_NOT_SUPPORTED]
+        );
+        assert!(accepted.is_none());
+    }
+
+    #[tokio::test]
+    async fn socks4a_host_name() {
+        let request = b"\x04\x01\x01\xbb\x00\x00\x00\x01user\x00example.com\x00";
+        let (reply, accepted) = negotiate(request, None).await;
+        assert_eq!(reply, [0x00, SOCKS4_GRANTED, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(accepted.unwrap().0.as_str(), "example.com:443");
+
+        let request = b"\x04\x01\x01\xbb\x0a\x00\x00\x01\x00";
+        let (_, accepted) = negotiate(request, None).await;
+        assert_eq!(accepted.unwrap().0.as_str(), "10.0.0.1:443");
+    }
+
+    #[tokio::test]
+    async fn socks4_refused_with_auth() {
+        let request = b"\x04\x01\x01\xbb\x0a\x00\x00\x01\x00";
+        let (reply, accepted) = negotiate(request, Some("user:pass")).await;
+        assert_eq!(reply, [0x00, SOCKS4_REJECTED, 0, 0, 0, 0, 0, 0]);
+        assert!(accepted.is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::error::{Error, TimeoutKind};
+use http::StatusCode;
+use hyper::{
+    server::{
+        accept::Accept,
+        conn::{AddrIncoming, AddrStream},
+    },
+    Body, Response,
+};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// Limits on how long upstream connections and requests may take, and how long client
+/// connections and tunnels may sit idle. Zero disables a limit.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Connecting to the upstream server or proxy, including name resolution.
+    pub connect: Duration,
+
+    /// Receiving the response headers, counted from having sent the whole request upstream.
+    pub response_headers: Duration,
+
+    /// The whole upstream exchange, until the response body has been received.
+    pub request: Duration,
+
+    /// Waiting for the next request on a client connection.
+    pub client_idle: Duration,
+
+    /// Relaying nothing in either direction of a tunnel.
+    pub tunnel_idle: Duration,
+
+    /// Waiting for the client of an intercepted tunnel to send its first bytes, after which the
+    /// server is assumed to speak first and the tunnel is relayed untouched.
+    pub server_first: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            response_headers: Duration::from_secs(60),
+            request: Duration::ZERO,
+            client_idle: Duration::from_secs(120),
+            tunnel_idle: Duration::from_secs(600),
+            server_first: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Timeouts {
+    /// The limit of `kind`, if enabled.
+    pub(crate) fn get(&self, kind: TimeoutKind) -> Option<Duration> {
+        let timeout = match kind {
+            TimeoutKind::Connect => self.connect,
+            TimeoutKind::ResponseHeaders => self.response_headers,
+            TimeoutKind::Request => self.request,
+            TimeoutKind::ClientIdle => self.client_idle,
+            TimeoutKind::TunnelIdle => self.tunnel_idle,
+        };
+        (!timeout.is_zero()).then_some(timeout)
+    }
+}
+
+/// An I/O error reporting that the limit of `kind` was exceeded.
+fn timed_out(kind: TimeoutKind, timeout: Duration) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, Error::Timeout(kind, timeout))
+}
+
+/// Returns `true` if `err` reports exceeding one of the [`Timeouts`].
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<Error>())
+        .is_some_and(|err| matches!(err, Error::Timeout(..)))
+}
+
+/// Run `future` within the limit of `kind`, if enabled.
+pub(crate) async fn within<F, T>(timeouts: &Timeouts, kind: TimeoutKind, future: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match timeouts.get(kind) {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .unwrap_or_else(|_| Err(timed_out(kind, timeout))),
+        None => future.await,
+    }
+}
+
+/// Requests in flight on a client connection, which is only idle while there are none.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    /// Count a request as in flight until `response` answers it, and for good if the answer
+    /// upgrades the connection, since tunnels have their own idle timeout. `connect` tells
+    /// whether the request is a CONNECT.
+    pub(crate) async fn track<F, E>(&self, connect: bool, response: F) -> Result<Response<Body>, E>
+    where
+        F: Future<Output = Result<Response<Body>, E>>,
+    {
+        let guard = InFlightGuard::new(self.clone());
+        let result = response.await;
+
+        if let Ok(res) = &result {
+            if res.status() == StatusCode::SWITCHING_PROTOCOLS
+                || (connect && res.status().is_success())
+            {
+                std::mem::forget(guard);
+            }
+        }
+
+        result
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == 0
+    }
+}
+
+/// Counts a request in flight until dropped, also when the client cancels it.
+struct InFlightGuard(InFlight);
+
+impl InFlightGuard {
+    fn new(in_flight: InFlight) -> Self {
+        in_flight.0.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        (self.0).0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A stream that fails reads with a timeout, logging it, once nothing was read or written for the
+/// limit of its kind while no request was in flight.
+///
+/// Wrapping one side of a relay is enough, since everything relayed is read from or written to
+/// it.
+pub(crate) struct IdleTimeout<I> {
+    io: I,
+    kind: TimeoutKind,
+    timer: Option<(Duration, Pin<Box<Sleep>>)>,
+    in_flight: InFlight,
+    description: String,
+    expired: bool,
+}
+
+impl<I> IdleTimeout<I> {
+    /// Wrap `io`, limited by the timeout of `kind` if enabled, and described as `description` in
+    /// the log.
+    pub(crate) fn new(io: I, kind: TimeoutKind, timeouts: &Timeouts, description: String) -> Self {
+        let timer = timeouts
+            .get(kind)
+            .map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout))));
+
+        Self {
+            io,
+            kind,
+            timer,
+            in_flight: InFlight::default(),
+            description,
+            expired: false,
+        }
+    }
+
+    /// The requests in flight on this connection, to be tracked by the service serving it.
+    pub(crate) fn in_flight(&self) -> InFlight {
+        self.in_flight.clone()
+    }
+
+    fn reset(&mut self) {
+        if let Some((timeout, sleep)) = &mut self.timer {
+            sleep.as_mut().reset(Instant::now() + *timeout);
+        }
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for IdleTimeout<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Poll::Ready(result) = Pin::new(&mut this.io).poll_read(cx, buf) {
+            this.reset();
+            return Poll::Ready(result);
+        }
+
+        // Waiting on a response is not idling, the idle time starts once it has been written
+        if !this.in_flight.is_idle() {
+            this.reset();
+            return Poll::Pending;
+        }
+
+        let Some((timeout, sleep)) = &mut this.timer else {
+            return Poll::Pending;
+        };
+        if sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let err = timed_out(this.kind, *timeout);
+        if !this.expired {
+            this.expired = true;
+            tracing::info!("Closing {}: {}", this.description, err);
+        }
+        Poll::Ready(Err(err))
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<I> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.io).poll_write(cx, buf);
+        if result.is_ready() {
+            this.reset();
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+/// Accepts client connections that are closed once idle for the client idle timeout.
+pub(crate) struct IdleIncoming {
+    incoming: AddrIncoming,
+    timeouts: Timeouts,
+}
+
+impl IdleIncoming {
+    pub(crate) fn new(incoming: AddrIncoming, timeouts: Timeouts) -> Self {
+        Self { incoming, timeouts }
+    }
+}
+
+impl Accept for IdleIncoming {
+    type Conn = IdleTimeout<AddrStream>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.incoming)
+            .poll_accept(cx)
+            .map_ok(|stream| {
+                let description = format!("client connection from {}", stream.remote_addr());
+                IdleTimeout::new(stream, TimeoutKind::ClientIdle, &this.timeouts, description)
+            })
+    }
+}
@@ -0,0 +1,189 @@
+use super::{
+    accept,
+    mitm::{ConnectAction, MitmProxy},
+    sniff::{self, Protocol},
+};
+use http::uri::Authority;
+use nix::{
+    ifaddrs::getifaddrs,
+    sys::socket::{getsockopt, setsockopt, sockopt},
+};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Bind a listener for connections redirected by the firewall.
+///
+/// `IP_TRANSPARENT` is set when permitted, so that TPROXY rules can deliver connections to it as
+/// well as REDIRECT rules.
+pub(crate) fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    if let Err(e) = setsockopt(&socket, sockopt::IpTransparent, &true) {
+        tracing::debug!("IP_TRANSPARENT not set, TPROXY rules will not work: {}", e);
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Accept connections redirected to `listener` and hand them to `mitm_proxy` as if they were
+/// CONNECTs to their original destination.
+///
+/// TLS connections are addressed by the SNI of their ClientHello, so that host rules and issued
+/// certificates use the name the client asked for. Everything else is addressed by the original
+/// destination address.
+pub(crate) async fn serve(listener: TcpListener, mitm_proxy: MitmProxy) {
+    let listen_addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            tracing::error!("Failed to get transparent listener address: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        let (stream, peer) = accept(&listener, "transparent").await;
+
+        let mitm_proxy = mitm_proxy.clone();
+        tokio::spawn(async move {
+            let dst = match original_dst(&stream) {
+                Ok(dst) => dst,
+                Err(e) => {
+                    tracing::debug!("Failed to get original destination of {}: {}", peer, e);
+                    return;
+                }
+            };
+
+            // Without a firewall rule the destination is the listener itself, and forwarding
+            // there would loop.
+            if is_listener(dst, listen_addr) {
+                tracing::warn!(
+                    "Dropping connection from {} addressed to the transparent listener itself",
+                    peer
+                );
+                return;
+            }
+
+            serve_connection(stream, dst, mitm_proxy).await
+        });
+    }
+}
+
+async fn serve_connection(stream: TcpStream, dst: SocketAddr, mitm_proxy: MitmProxy) {
+    let (protocol, io) = match sniff::sniff(stream, mitm_proxy.timeouts.server_first).await {
+        Ok(sniffed) => sniffed,
+        Err(e) => {
+            tracing::error!("Failed to read from transparent connection: {}", e);
+            return;
+        }
+    };
+
+    let dst_authority = match Authority::try_from(dst.to_string()) {
+        Ok(authority) => authority,
+        Err(e) => {
+            tracing::error!("Invalid original destination {}: {}", dst, e);
+            return;
+        }
+    };
+    // The host name the client asked for, on the port it connected to
+    let host = match &protocol {
+        Protocol::Tls(Some(hello)) => hello.server_name.clone(),
+        Protocol::Http1(Some(host)) => Authority::try_from(host.as_str())
+            .ok()
+            .map(|host| host.host().to_owned()),
+        _ => None,
+    };
+    let authority = host
+        .and_then(|host| Authority::try_from(format!("{host}:{}", dst.port())).ok())
+        .unwrap_or_else(|| dst_authority.clone());
+    // Requests of this connection for the name still go to the address the client connected to
+    let mitm_proxy = match mitm_proxy.with_destination(authority.host(), dst.ip()) {
+        Ok(mitm_proxy) => mitm_proxy,
+        Err(e) => {
+            tracing::error!("Failed to create client for {}: {}", dst, e);
+            return;
+        }
+    };
+    tracing::debug!(
+        "Transparent connection to {} ({}) protocol: {}",
+        dst,
+        authority,
+        protocol
+    );
+
+    match mitm_proxy.connect_action(&authority) {
+        ConnectAction::Intercept => mitm_proxy.intercept(protocol, io, authority).await,
+        // Tunnel to the address the client connected to rather than resolving the SNI again
+        ConnectAction::Passthrough => {
+            mitm_proxy
+                .serve_tunnel(io, dst_authority, ConnectAction::Passthrough)
+                .await
+        }
+        ConnectAction::Block => {}
+    }
+}
+
+/// The destination the client connected to before the firewall redirected it.
+///
+/// REDIRECT rules record it in `SO_ORIGINAL_DST`. TPROXY rules leave the connection addressed to
+/// its original destination, which is then the local address.
+fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let local_addr = stream.local_addr()?;
+
+    let dst = match local_addr {
+        SocketAddr::V4(_) => getsockopt(stream, sockopt::OriginalDst).map(|addr| {
+            SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            ))
+        }),
+        SocketAddr::V6(_) => getsockopt(stream, sockopt::Ip6tOriginalDst).map(|addr| {
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                0,
+                0,
+            ))
+        }),
+    };
+
+    Ok(dst.unwrap_or(local_addr))
+}
+
+/// Returns `true` if `dst` is the address the transparent listener is bound to.
+fn is_listener(dst: SocketAddr, listen_addr: SocketAddr) -> bool {
+    if dst.port() != listen_addr.port() {
+        return false;
+    }
+
+    let ip = dst.ip().to_canonical();
+    if !listen_addr.ip().is_unspecified() {
+        return ip == listen_addr.ip().to_canonical();
+    }
+
+    ip.is_loopback() || local_addrs().contains(&ip)
+}
+
+/// The addresses of the local network interfaces.
+fn local_addrs() -> Vec<IpAddr> {
+    let Ok(addrs) = getifaddrs() else {
+        return Vec::new();
+    };
+
+    addrs
+        .filter_map(|ifaddr| {
+            let addr = ifaddr.address?;
+            if let Some(addr) = addr.as_sockaddr_in() {
+                Some(IpAddr::V4(*SocketAddrV4::from(*addr).ip()))
+            } else {
+                addr.as_sockaddr_in6()
+                    .map(|addr| IpAddr::V6(addr.ip()).to_canonical())
+            }
+        })
+        .collect()
+}
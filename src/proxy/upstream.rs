@@ -0,0 +1,141 @@
+use super::{connector::Connector, timeout};
+use crate::error::{Error, TimeoutKind};
+use http::{uri::Authority, Uri};
+use std::{io, sync::Arc, time::SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    rustls::{
+        client::{ServerCertVerified, ServerCertVerifier},
+        Certificate, CertificateError, ClientConfig, ServerName,
+    },
+    TlsConnector,
+};
+
+/// A byte stream to an upstream server.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Open a connection to the server of an absolute `uri`, speaking TLS with `tls_config` for
+/// `https` and `wss`. The TLS handshake is limited by the connect timeout, like connecting is.
+///
+/// `tls_config` should only offer HTTP/1.1 via ALPN, since the stream is driven by an HTTP/1.1
+/// client.
+pub(crate) async fn connect(
+    connector: &Connector,
+    tls_config: Arc<ClientConfig>,
+    uri: &Uri,
+) -> Result<Box<dyn Stream>, Error> {
+    let authority = uri
+        .authority()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "uri has no authority"))?;
+    let tls = matches!(uri.scheme_str(), Some("https" | "wss"));
+    let default_port = if tls { 443 } else { 80 };
+
+    let stream = connector
+        .connect(
+            authority.host(),
+            authority.port_u16().unwrap_or(default_port),
+            !tls,
+        )
+        .await?;
+    if !tls {
+        return Ok(Box::new(stream));
+    }
+
+    let handshake = TlsConnector::from(tls_config).connect(server_name(authority)?, stream);
+    let stream = timeout::within(connector.timeouts(), TimeoutKind::Connect, handshake).await?;
+    Ok(Box::new(stream))
+}
+
+/// Connect to the upstream server at `authority`, asking for `server_name`, and return the leaf
+/// certificate it presents.
+///
+/// The certificate is not verified, it is only read so that it can be mimicked.
+pub(crate) async fn fetch_certificate(
+    connector: &Connector,
+    authority: &Authority,
+    server_name: &str,
+) -> Result<Certificate, Error> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoVerifier))
+        .with_no_client_auth();
+
+    handshake(connector, Arc::new(config), authority, server_name).await
+}
+
+/// Connect to the upstream server at `authority`, verifying it for `server_name` with
+/// `tls_config`, and return the leaf certificate it presents.
+pub(crate) async fn verify_certificate(
+    connector: &Connector,
+    tls_config: Arc<ClientConfig>,
+    authority: &Authority,
+    server_name: &str,
+) -> Result<Certificate, Error> {
+    handshake(connector, tls_config, authority, server_name).await
+}
+
+/// Complete a TLS handshake for `server_name` with the upstream server at `authority` and return
+/// its leaf certificate. The handshake is limited by the connect timeout, like connecting is.
+async fn handshake(
+    connector: &Connector,
+    tls_config: Arc<ClientConfig>,
+    authority: &Authority,
+    server_name: &str,
+) -> Result<Certificate, Error> {
+    let server_name = parse_server_name(server_name)?;
+
+    let stream = connector
+        .connect(authority.host(), authority.port_u16().unwrap_or(443), false)
+        .await?;
+    let handshake = TlsConnector::from(tls_config).connect(server_name, stream);
+    let stream = timeout::within(connector.timeouts(), TimeoutKind::Connect, handshake).await?;
+
+    let (_, conn) = stream.get_ref();
+    conn.peer_certificates()
+        .and_then(|certs| certs.first().cloned())
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "upstream sent no certificate").into()
+        })
+}
+
+/// The reason the upstream server certificate was rejected, if `err` is a rejection.
+pub(crate) fn certificate_error(err: &Error) -> Option<&CertificateError> {
+    let Error::IO(err) = err else {
+        return None;
+    };
+
+    match err.get_ref()?.downcast_ref::<rustls::Error>()? {
+        rustls::Error::InvalidCertificate(err) => Some(err),
+        _ => None,
+    }
+}
+
+/// The TLS server name of an authority.
+fn server_name(authority: &Authority) -> Result<ServerName, Error> {
+    parse_server_name(authority.host())
+}
+
+/// The TLS server name of a host, which may be a bracketed IPv6 literal.
+fn parse_server_name(host: &str) -> Result<ServerName, Error> {
+    ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e).into())
+}
+
+/// Accepts any server certificate.
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
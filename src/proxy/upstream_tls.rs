@@ -0,0 +1,240 @@
+use super::matcher::{HostPattern, HostRule, HostRules};
+use crate::error::Error;
+use base64::Engine;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, CertificateError, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore,
+    ServerName,
+};
+use std::{fmt, io, str::FromStr, sync::Arc, time::SystemTime};
+
+/// How certificates of upstream servers are verified, and the client certificate presented to
+/// them.
+#[derive(Clone, Default)]
+pub struct UpstreamTls {
+    /// Trust anchors accepted in addition to the built-in roots.
+    pub roots: Vec<Certificate>,
+
+    /// Hosts whose certificate chains are not verified.
+    pub insecure: Vec<HostPattern>,
+
+    /// Public keys the leaf certificate presented by matching hosts must have one of.
+    pub pins: HostRules<SpkiPins>,
+
+    /// Certificate chain and private key presented to servers that request a client certificate.
+    pub client_auth: Option<(Vec<Certificate>, PrivateKey)>,
+}
+
+impl UpstreamTls {
+    /// A client configuration verifying servers by these settings and offering `alpn_protocols`.
+    pub(crate) fn client_config(&self, alpn_protocols: &[&[u8]]) -> Result<ClientConfig, Error> {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        for root in &self.roots {
+            roots.add(root).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid upstream CA certificate: {e}"),
+                )
+            })?;
+        }
+
+        let verifier = UpstreamVerifier {
+            webpki: WebPkiVerifier::new(roots, None),
+            insecure: self.insecure.clone(),
+            pins: self.pins.clone(),
+        };
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier));
+        let mut config = match &self.client_auth {
+            Some((certs, key)) => builder.with_client_auth_cert(certs.clone(), key.clone())?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+
+        Ok(config)
+    }
+}
+
+/// SHA-256 digests of subject public key infos, parsed from a comma separated list of
+/// `sha256/BASE64`, the format of `curl --pinnedpubkey`.
+#[derive(Clone, Debug)]
+pub struct SpkiPins(Arc<[[u8; 32]]>);
+
+impl SpkiPins {
+    fn contains(&self, cert: &Certificate) -> bool {
+        spki_sha256(cert).is_some_and(|digest| self.0.contains(&digest))
+    }
+}
+
+impl FromStr for SpkiPins {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|pin| {
+                let pin = pin.trim();
+                pin.strip_prefix("sha256/")
+                    .and_then(|digest| {
+                        base64::engine::general_purpose::STANDARD
+                            .decode(digest)
+                            .ok()
+                    })
+                    .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+                    .ok_or_else(|| format!("invalid pin '{pin}', expected sha256/BASE64"))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|pins| Self(pins.into()))
+    }
+}
+
+impl fmt::Display for SpkiPins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, digest) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            let digest = base64::engine::general_purpose::STANDARD.encode(digest);
+            write!(f, "sha256/{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for HostRule<SpkiPins> {
+    type Err = String;
+
+    /// Parse `PATTERN=PINS`, where the pattern ends at the first `=sha256/`, since base64 pins
+    /// may end with `=`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, pins) = s
+            .find("=sha256/")
+            .map(|at| (&s[..at], &s[at + 1..]))
+            .ok_or_else(|| format!("expected PATTERN=sha256/BASE64, got '{s}'"))?;
+
+        Ok(HostRule {
+            pattern: pattern.parse()?,
+            value: pins.trim().parse()?,
+        })
+    }
+}
+
+/// The SHA-256 digest of the subject public key info of a DER encoded certificate.
+fn spki_sha256(cert: &Certificate) -> Option<[u8; 32]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert.public_key().raw);
+    digest.as_ref().try_into().ok()
+}
+
+/// Verifies chains against the trust anchors unless the host is insecure, then checks the leaf
+/// against the pins of the host. Intermediates are whatever the server chose to send, so they
+/// cannot satisfy a pin, as with `curl --pinnedpubkey`.
+struct UpstreamVerifier {
+    webpki: WebPkiVerifier,
+    insecure: Vec<HostPattern>,
+    pins: HostRules<SpkiPins>,
+}
+
+impl ServerCertVerifier for UpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_owned(),
+            ServerName::IpAddress(ip) => ip.to_string(),
+            _ => String::new(),
+        };
+
+        if !self.insecure.iter().any(|pattern| pattern.matches(&host)) {
+            self.webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        if let Some(pins) = self.pins.get(&host) {
+            if !pins.contains(end_entity) {
+                return Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                    Arc::new(Error::PinMismatch(host)),
+                )));
+            }
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert(name: &str) -> Certificate {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+        Certificate(cert.serialize_der().unwrap())
+    }
+
+    /// A verifier skipping chain verification for `example.com`, pinned to the key of `pinned`.
+    fn verifier(pinned: &Certificate) -> UpstreamVerifier {
+        let pins = SpkiPins(vec![spki_sha256(pinned).unwrap()].into());
+        UpstreamVerifier {
+            webpki: WebPkiVerifier::new(RootCertStore::empty(), None),
+            insecure: vec!["example.com".parse().unwrap()],
+            pins: vec![HostRule {
+                pattern: "example.com".parse().unwrap(),
+                value: pins,
+            }]
+            .into(),
+        }
+    }
+
+    fn verify(
+        verifier: &UpstreamVerifier,
+        leaf: &Certificate,
+        intermediates: &[Certificate],
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        verifier.verify_server_cert(
+            leaf,
+            intermediates,
+            &ServerName::try_from("example.com").unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        )
+    }
+
+    #[test]
+    fn pin_matches_leaf() {
+        let leaf = cert("example.com");
+        assert!(verify(&verifier(&leaf), &leaf, &[]).is_ok());
+    }
+
+    #[test]
+    fn pinned_intermediate_does_not_satisfy_pin() {
+        let leaf = cert("example.com");
+        let intermediate = cert("Pinned Intermediate");
+        let result = verify(&verifier(&intermediate), &leaf, &[intermediate]);
+        assert!(matches!(
+            result,
+            Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                _
+            )))
+        ));
+    }
+}
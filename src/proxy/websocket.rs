@@ -0,0 +1,300 @@
+use super::{
+    connector::Connector,
+    timeout::{self, IdleTimeout, Timeouts},
+    upstream,
+};
+use crate::error::{Error, TimeoutKind};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use http::{header, HeaderValue, Request, Response, StatusCode, Uri, Version};
+use hyper::{client::conn, upgrade::Upgraded, Body};
+use regex::Regex;
+use std::{borrow::Cow, fmt, sync::Arc};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_tungstenite::{
+    tungstenite::{protocol::Role, Error as WsError},
+    WebSocketStream,
+};
+
+pub use tokio_tungstenite::tungstenite::Message;
+
+/// Direction in which a WebSocket message is relayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebSocketDirection {
+    /// From the client to the upstream server
+    ClientToServer,
+    /// From the upstream server to the client
+    ServerToClient,
+}
+
+impl fmt::Display for WebSocketDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketDirection::ClientToServer => f.write_str("client -> server"),
+            WebSocketDirection::ServerToClient => f.write_str("server -> client"),
+        }
+    }
+}
+
+/// Hook invoked for every WebSocket message relayed through the proxy.
+pub trait WebSocketHook: Send + Sync {
+    /// Inspect a message before it is forwarded.
+    ///
+    /// Return the message, possibly rewritten, to forward it, or `None` to drop it.
+    fn on_message(
+        &self,
+        uri: &Uri,
+        direction: WebSocketDirection,
+        message: Message,
+    ) -> Option<Message>;
+}
+
+/// Drops and rewrites text messages by regular expression, in both directions. Other messages are
+/// relayed unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct MessageRules {
+    /// Text messages matching any of these expressions are dropped
+    pub drop: Vec<Regex>,
+    /// Matches of each expression are replaced in turn, `$1` or `${name}` inserting a group
+    pub replace: Vec<(Regex, String)>,
+}
+
+impl MessageRules {
+    /// Returns `true` if the rules leave every message unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.drop.is_empty() && self.replace.is_empty()
+    }
+}
+
+impl WebSocketHook for MessageRules {
+    fn on_message(
+        &self,
+        _uri: &Uri,
+        _direction: WebSocketDirection,
+        message: Message,
+    ) -> Option<Message> {
+        let Message::Text(mut text) = message else {
+            return Some(message);
+        };
+
+        if self.drop.iter().any(|regex| regex.is_match(&text)) {
+            return None;
+        }
+        for (regex, replacement) in &self.replace {
+            if let Cow::Owned(replaced) = regex.replace_all(&text, replacement.as_str()) {
+                text = replaced;
+            }
+        }
+
+        Some(Message::Text(text))
+    }
+}
+
+/// Returns `true` if `req` asks to upgrade the connection to a WebSocket.
+pub(crate) fn is_upgrade_request<T>(req: &Request<T>) -> bool {
+    let has_token = |name, token: &str| {
+        req.headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+
+    has_token(header::CONNECTION, "upgrade") && has_token(header::UPGRADE, "websocket")
+}
+
+/// Forward the WebSocket handshake of `req` to its upstream server, and relay messages between
+/// the client and the server once both connections have been upgraded.
+///
+/// Responses other than `101 Switching Protocols` are returned to the client unchanged. Waiting
+/// for the response is limited by the response headers timeout, and the relay is closed once no
+/// message was relayed for the tunnel idle timeout.
+pub(crate) async fn upgrade(
+    mut req: Request<Body>,
+    hook: Option<Arc<dyn WebSocketHook>>,
+    connector: &Connector,
+    tls_config: Arc<ClientConfig>,
+) -> Result<Response<Body>, Error> {
+    let uri = req.uri().clone();
+    let client_upgrade = hyper::upgrade::on(&mut req);
+
+    let (mut parts, body) = req.into_parts();
+    // Extensions such as permessage-deflate are not understood by the relay
+    parts.headers.remove(header::SEC_WEBSOCKET_EXTENSIONS);
+    if let Some(authority) = uri.authority() {
+        let host = HeaderValue::from_str(authority.as_str()).map_err(http::Error::from)?;
+        parts.headers.insert(header::HOST, host);
+    }
+    parts.uri = uri
+        .path_and_query()
+        .cloned()
+        .map_or_else(|| Uri::from_static("/"), Uri::from);
+    parts.version = Version::HTTP_11;
+
+    let timeouts = *connector.timeouts();
+    let stream = upstream::connect(connector, tls_config, &uri).await?;
+    let (mut sender, connection) = conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::debug!("WebSocket upstream connection error: {}", e);
+        }
+    });
+
+    let response = sender.send_request(Request::from_parts(parts, body));
+    let mut res = match timeouts.get(TimeoutKind::ResponseHeaders) {
+        Some(timeout) => tokio::time::timeout(timeout, response)
+            .await
+            .map_err(|_| Error::Timeout(TimeoutKind::ResponseHeaders, timeout))??,
+        None => response.await?,
+    };
+    if res.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(res);
+    }
+
+    let server_upgrade = hyper::upgrade::on(&mut res);
+    tokio::spawn(async move {
+        match tokio::try_join!(client_upgrade, server_upgrade) {
+            Ok((client, server)) => relay(uri, client, server, hook, &timeouts).await,
+            Err(e) => tracing::error!("WebSocket upgrade error: {}", e),
+        }
+    });
+
+    Ok(res)
+}
+
+/// Relay messages between the upgraded client and server connections until both are closed, or
+/// nothing was relayed for the tunnel idle timeout of `timeouts`.
+async fn relay(
+    uri: Uri,
+    client: Upgraded,
+    server: Upgraded,
+    hook: Option<Arc<dyn WebSocketHook>>,
+    timeouts: &Timeouts,
+) {
+    // Every relayed message is read from or written to the client
+    let client = IdleTimeout::new(
+        client,
+        TimeoutKind::TunnelIdle,
+        timeouts,
+        format!("WebSocket {uri}"),
+    );
+    let client = WebSocketStream::from_raw_socket(client, Role::Server, None).await;
+    let server = WebSocketStream::from_raw_socket(server, Role::Client, None).await;
+    let (client_tx, client_rx) = client.split();
+    let (server_tx, server_rx) = server.split();
+
+    tracing::debug!("WebSocket {} opened", uri);
+    tokio::join!(
+        forward(
+            &uri,
+            WebSocketDirection::ClientToServer,
+            hook.as_deref(),
+            client_rx,
+            server_tx
+        ),
+        forward(
+            &uri,
+            WebSocketDirection::ServerToClient,
+            hook.as_deref(),
+            server_rx,
+            client_tx
+        ),
+    );
+    tracing::debug!("WebSocket {} closed", uri);
+}
+
+/// Forward messages from `rx` to `tx`, then close `tx` so that the other direction ends as well.
+///
+/// Pings and pongs are answered by each side of the relay and are not forwarded.
+async fn forward<R, T>(
+    uri: &Uri,
+    direction: WebSocketDirection,
+    hook: Option<&dyn WebSocketHook>,
+    mut rx: R,
+    mut tx: T,
+) where
+    R: Stream<Item = Result<Message, WsError>> + Unpin,
+    T: Sink<Message, Error = WsError> + Unpin,
+{
+    while let Some(message) = rx.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(WsError::Io(e)) if timeout::is_timeout(&e) => break,
+            Err(e) => {
+                tracing::debug!("WebSocket {} {} read error: {}", uri, direction, e);
+                break;
+            }
+        };
+
+        tracing::debug!("WebSocket {} {}: {}", uri, direction, describe(&message));
+        if let Message::Text(text) = &message {
+            tracing::trace!("WebSocket {} {}: {}", uri, direction, text);
+        }
+
+        if message.is_ping() || message.is_pong() {
+            continue;
+        }
+
+        let message = match hook {
+            Some(hook) => match hook.on_message(uri, direction, message) {
+                Some(message) => message,
+                None => {
+                    tracing::debug!("WebSocket {} {}: dropped by hook", uri, direction);
+                    continue;
+                }
+            },
+            None => message,
+        };
+
+        let is_close = message.is_close();
+        if let Err(e) = tx.send(message).await {
+            if !is_close {
+                tracing::debug!("WebSocket {} {} write error: {}", uri, direction, e);
+            }
+            break;
+        }
+    }
+
+    let _ = tx.close().await;
+}
+
+/// A short description of a message for logging.
+fn describe(message: &Message) -> String {
+    match message {
+        Message::Text(text) => format!("text ({} bytes)", text.len()),
+        Message::Binary(data) => format!("binary ({} bytes)", data.len()),
+        Message::Ping(_) => "ping".to_owned(),
+        Message::Pong(_) => "pong".to_owned(),
+        Message::Close(Some(frame)) => format!("close ({} {})", frame.code, frame.reason),
+        Message::Close(None) => "close".to_owned(),
+        Message::Frame(frame) => format!("frame ({} bytes)", frame.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(rules: &MessageRules, message: Message) -> Option<Message> {
+        let uri = Uri::from_static("ws://example.com/chat");
+        rules.on_message(&uri, WebSocketDirection::ClientToServer, message)
+    }
+
+    #[test]
+    fn message_rules_drop_and_replace_text() {
+        let rules = MessageRules {
+            drop: vec![Regex::new("^ping$").unwrap()],
+            replace: vec![(Regex::new(r"token=(\w+)").unwrap(), "token=<$1>".to_owned())],
+        };
+
+        assert_eq!(apply(&rules, Message::Text("ping".to_owned())), None);
+        assert_eq!(
+            apply(&rules, Message::Text("a token=abc b".to_owned())),
+            Some(Message::Text("a token=<abc> b".to_owned()))
+        );
+        assert_eq!(
+            apply(&rules, Message::Binary(b"ping".to_vec())),
+            Some(Message::Binary(b"ping".to_vec()))
+        );
+    }
+}
@@ -1,9 +1,13 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use crate::proxy::{CertificateAuthority, Proxy};
+use crate::proxy::{
+    read_certs, read_hosts_file, read_private_key,
+    websocket::{MessageRules, WebSocketHook},
+    CertificateAuthority, DnsServer, Proxy, ReverseProxy, Timeouts, UpstreamTls,
+};
 use crate::{cagen, BootArgs};
-use anyhow::{Context, Result};
-use tokio::fs;
+use anyhow::{bail, Context, Result};
+use regex::Regex;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 pub struct Serve(pub BootArgs);
@@ -25,45 +29,155 @@ impl Serve {
             .with(tracing_subscriber::fmt::layer())
             .init();
 
-        // Generate a certificate authority
-        if !self.0.cert.exists() || !self.0.key.exists() {
-            cagen::gen_ca(&self.0.cert, &self.0.key);
+        // Generate a certificate authority, unless half of one is left
+        match (self.0.cert.exists(), self.0.key.exists()) {
+            (false, false) => cagen::gen_ca(&self.0.cert, &self.0.key)?,
+            (true, true) => {}
+            (cert_exists, _) => {
+                let (missing, present) = if cert_exists {
+                    (&self.0.key, &self.0.cert)
+                } else {
+                    (&self.0.cert, &self.0.key)
+                };
+                bail!(
+                    "{} is missing but {} exists, restore it or replace both with `devicecheck ca generate --force`",
+                    missing.display(),
+                    present.display()
+                );
+            }
         }
 
-        // Read the private key and certificate
+        // Load the certificate authority
         tracing::info!("CA Private key use: {}", self.0.key.display());
-        let private_key_bytes = fs::read(self.0.key)
-            .await
-            .context("ca private key file path not valid!")?;
-        let private_key = rustls_pemfile::pkcs8_private_keys(&mut private_key_bytes.as_slice())
-            .context("Failed to parse private key")?;
-        let key = rustls::PrivateKey(private_key[0].clone());
-
-        // Read the certificate
         tracing::info!("CA Certificate use: {}", self.0.cert.display());
-        let ca_cert_bytes = fs::read(self.0.cert)
-            .await
-            .context("ca cert file path not valid!")?;
-        let ca_cert = rustls_pemfile::certs(&mut ca_cert_bytes.as_slice())
-            .context("Failed to parse CA certificate")?;
-        let cert = rustls::Certificate(ca_cert[0].clone());
-
-        // Gnerate a certificate authority
-        let ca = CertificateAuthority::new(
-            key,
-            cert,
-            String::from_utf8(ca_cert_bytes).context("Failed to parse CA certificate")?,
+        if let Some(ca_chain) = &self.0.ca_chain {
+            tracing::info!("CA Chain use: {}", ca_chain.display());
+        }
+        let passphrase = cagen::read_passphrase(&self.0.passphrase)?;
+        let mut ca = CertificateAuthority::from_pem_files(
+            &self.0.cert,
+            &self.0.key,
+            self.0.ca_chain.as_deref(),
+            passphrase.as_deref(),
             1_000,
+            self.0.leaf_key_alg,
         )
         .context("Failed to create Certificate Authority")?;
+        tracing::info!("CA SHA-256 fingerprint: {}", ca.fingerprint());
+
+        if let Some(dir) = &self.0.cert_cache_dir {
+            tracing::info!("Certificate cache use: {}", dir.display());
+            ca = ca
+                .with_cert_cache_dir(dir)
+                .context("Failed to open certificate cache")?;
+        }
 
         tracing::info!("Http MITM Proxy listen on: http://{}", self.0.bind);
+        if let Some(socks_bind) = self.0.socks_bind {
+            tracing::info!("SOCKS MITM Proxy listen on: socks5://{}", socks_bind);
+        }
+        if let Some(transparent) = self.0.transparent {
+            tracing::info!("Transparent MITM Proxy listen on: {}", transparent);
+        }
+        let reverse = self
+            .0
+            .reverse
+            .zip(self.0.reverse_bind)
+            .map(|(origin, listen_addr)| {
+                let scheme = if self.0.reverse_tls { "https" } else { "http" };
+                tracing::info!(
+                    "Reverse Proxy listen on: {}://{}, forwarding to {}",
+                    scheme,
+                    listen_addr,
+                    origin
+                );
+                ReverseProxy {
+                    listen_addr,
+                    origin,
+                    tls: self.0.reverse_tls,
+                }
+            });
+
+        // Overrides from the command line come first, so that they win over the hosts file
+        let mut hosts = self.0.hosts;
+        if let Some(hosts_file) = &self.0.hosts_file {
+            tracing::info!("Hosts file use: {}", hosts_file.display());
+            hosts.extend(read_hosts_file(hosts_file)?);
+        }
+        if !matches!(self.0.dns_server, DnsServer::System) {
+            tracing::info!("DNS server use: {}", self.0.dns_server);
+        }
+
+        let mut upstream_tls = UpstreamTls {
+            insecure: self.0.insecure_upstreams,
+            pins: self.0.pins.into(),
+            ..Default::default()
+        };
+        for path in &self.0.upstream_cas {
+            tracing::info!("Upstream CA use: {}", path.display());
+            upstream_tls.roots.extend(
+                read_certs(path)
+                    .with_context(|| format!("Failed to read upstream CA {}", path.display()))?,
+            );
+        }
+        if let Some((cert, key)) = self
+            .0
+            .upstream_client_cert
+            .as_ref()
+            .zip(self.0.upstream_client_key.as_ref())
+        {
+            tracing::info!("Upstream client certificate use: {}", cert.display());
+            upstream_tls.client_auth = Some((
+                read_certs(cert).context("Failed to read upstream client certificate")?,
+                read_private_key(key, None).context("Failed to read upstream client key")?,
+            ));
+        }
+        for pattern in &upstream_tls.insecure {
+            tracing::warn!("Upstream certificates of {} are not verified", pattern);
+        }
+
+        let mut message_rules = MessageRules {
+            drop: self.0.ws_drops,
+            ..Default::default()
+        };
+        for pair in self.0.ws_replaces.chunks_exact(2) {
+            let regex = Regex::new(&pair[0])
+                .with_context(|| format!("Invalid WebSocket replace pattern {}", pair[0]))?;
+            message_rules.replace.push((regex, pair[1].clone()));
+        }
+        let websocket_hook =
+            (!message_rules.is_empty()).then(|| Arc::new(message_rules) as Arc<dyn WebSocketHook>);
 
         // Start the server
         Proxy::builder()
             .ca(Arc::new(ca))
             .listen_addr(self.0.bind)
             .proxy(self.0.proxy)
+            .routes(self.0.routes.into())
+            .health_check_interval(Duration::from_secs(self.0.health_check_interval))
+            .hosts(hosts.into())
+            .dns_server(self.0.dns_server)
+            .dns_cache_ttl(Duration::from_secs(self.0.dns_cache_ttl))
+            .timeouts(Timeouts {
+                connect: Duration::from_secs(self.0.connect_timeout),
+                response_headers: Duration::from_secs(self.0.response_timeout),
+                request: Duration::from_secs(self.0.request_timeout),
+                client_idle: Duration::from_secs(self.0.client_idle_timeout),
+                tunnel_idle: Duration::from_secs(self.0.tunnel_idle_timeout),
+                server_first: Duration::from_secs(self.0.server_first_timeout),
+            })
+            .upstream_tls(upstream_tls)
+            .mimic_upstream_cert(self.0.mimic_upstream_cert)
+            .verify_upstream_cert(self.0.verify_upstream_cert)
+            .alpn_protocols(self.0.alpn)
+            .http_versions(self.0.http_versions.into())
+            .connect_rules(self.0.rules.into())
+            .passthrough_after(self.0.passthrough_after)
+            .websocket_hook(websocket_hook)
+            .socks_listen_addr(self.0.socks_bind)
+            .socks_auth(self.0.socks_auth)
+            .transparent_listen_addr(self.0.transparent)
+            .reverse(reverse)
             .build()
             .start(shutdown_signal())
             .await